@@ -0,0 +1,334 @@
+//! JSON api for running the live server, under /admin.
+//!
+//! Every request needs `Authorization: Bearer <admin_token>`, without an admin_token in
+//! the config the whole api answers 404. Browsers cant put headers on a websocket, so the
+//! replay websocket also takes the token as `?access_token=<admin_token>`. Everything is a
+//! message to the `Server` actor, except the replays which are read from replay_dir.
+
+use crate::config::{self, Config, Settings};
+use crate::messages;
+use crate::protocol;
+use crate::server::{Playback, Replay, Server};
+use crate::spectator::Spectator;
+use actix::Addr;
+use actix_web::{http::header, http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::Deserialize;
+use serde_json::json;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/admin")
+            .route("/rooms", web::get().to(list_rooms))
+            .route("/rooms", web::post().to(create_room))
+            .route("/rooms/{name}", web::delete().to(close_room))
+            .route("/players/{id}/kick", web::post().to(kick))
+            .route("/players/{id}/ban", web::post().to(ban))
+            .route("/announce", web::post().to(announce))
+            .route("/settings", web::get().to(get_settings))
+            .route("/settings", web::patch().to(update_settings))
+            .route("/replays", web::get().to(list_replays))
+            .route("/replays/{file}", web::get().to(watch_replay)),
+    );
+}
+
+fn failed(status: StatusCode, detail: &str) -> HttpResponse {
+    HttpResponse::build(status).json(json!({ "error": detail }))
+}
+
+fn unavailable() -> HttpResponse {
+    failed(StatusCode::SERVICE_UNAVAILABLE, "server unavailable")
+}
+
+/// the response to send instead if the request doesnt have the admin token
+fn refuse(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    check_token(given, config)
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// like refuse, but the token may also be in the query, for websockets opened by a browser
+fn refuse_websocket(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    let query = web::Query::<TokenQuery>::from_query(req.query_string()).ok();
+    match query.as_ref().and_then(|q| q.access_token.as_deref()) {
+        Some(given) => check_token(Some(given), config),
+        None => refuse(req, config),
+    }
+}
+
+fn check_token(given: Option<&str>, config: &Config) -> Option<HttpResponse> {
+    let expected = match &config.admin_token {
+        Some(token) => token,
+        None => return Some(failed(StatusCode::NOT_FOUND, "admin api is disabled")),
+    };
+    match given {
+        Some(given) if config::same_secret(expected, given) => None,
+        _ => Some(failed(
+            StatusCode::UNAUTHORIZED,
+            "wrong or missing admin token",
+        )),
+    }
+}
+
+/// every room with its players, their positions, health and ping
+async fn list_rooms(
+    req: HttpRequest,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    match srv.send(messages::ListRoomInfo).await {
+        Ok(rooms) => HttpResponse::Ok().json(rooms),
+        Err(_) => unavailable(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateRoom {
+    name: String,
+}
+
+async fn create_room(
+    req: HttpRequest,
+    body: web::Json<CreateRoom>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let name = body.into_inner().name;
+    if name.is_empty() {
+        return failed(StatusCode::BAD_REQUEST, "room name is empty");
+    }
+    match srv
+        .send(messages::CreateRoomMessage { name: name.clone() })
+        .await
+    {
+        Ok(true) => HttpResponse::Created().json(json!({ "name": name })),
+        Ok(false) => failed(StatusCode::CONFLICT, "room already exists"),
+        Err(_) => unavailable(),
+    }
+}
+
+/// the players in it are moved to the default room
+async fn close_room(
+    req: HttpRequest,
+    name: web::Path<String>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let name = name.into_inner();
+    match srv.send(messages::ShutRoomMessage { name }).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(detail)) => failed(StatusCode::BAD_REQUEST, &detail),
+        Err(_) => unavailable(),
+    }
+}
+
+async fn moderate(
+    srv: &Addr<Server>,
+    player_id: usize,
+    action: messages::ModAction,
+) -> HttpResponse {
+    let msg = messages::ModerateMessage {
+        by: String::from("admin api"),
+        player_id,
+        action,
+    };
+    match srv.send(msg).await {
+        Ok(Ok(done)) => HttpResponse::Ok().json(json!({ "done": done })),
+        Ok(Err(detail)) => failed(StatusCode::NOT_FOUND, &detail),
+        Err(_) => unavailable(),
+    }
+}
+
+#[derive(Deserialize)]
+struct Kick {
+    #[serde(default)]
+    reason: String,
+}
+
+async fn kick(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    body: web::Json<Kick>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let reason = body.into_inner().reason;
+    moderate(&srv, id.into_inner(), messages::ModAction::Kick { reason }).await
+}
+
+/// secs None bans for good. bans are on the address the player connected from, ip false is refused
+#[derive(Deserialize)]
+struct Ban {
+    #[serde(default)]
+    reason: String,
+    secs: Option<u64>,
+    #[serde(default = "yes")]
+    ip: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+async fn ban(
+    req: HttpRequest,
+    id: web::Path<usize>,
+    body: web::Json<Ban>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let Ban { reason, secs, ip } = body.into_inner();
+    let action = messages::ModAction::Ban {
+        reason,
+        duration: secs.map(Duration::from_secs),
+        ip,
+    };
+    moderate(&srv, id.into_inner(), action).await
+}
+
+/// room None says it in every room
+#[derive(Deserialize)]
+struct Announce {
+    text: String,
+    room: Option<String>,
+}
+
+async fn announce(
+    req: HttpRequest,
+    body: web::Json<Announce>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let Announce { text, room } = body.into_inner();
+    if text.is_empty() {
+        return failed(StatusCode::BAD_REQUEST, "text is empty");
+    }
+    match srv.send(messages::AnnounceMessage { room, text }).await {
+        Ok(Ok(rooms)) => HttpResponse::Ok().json(json!({ "rooms": rooms })),
+        Ok(Err(detail)) => failed(StatusCode::NOT_FOUND, &detail),
+        Err(_) => unavailable(),
+    }
+}
+
+async fn get_settings(
+    req: HttpRequest,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    match srv.send(messages::GetSettingsMessage).await {
+        Ok(settings) => HttpResponse::Ok().json(settings),
+        Err(_) => unavailable(),
+    }
+}
+
+/// only the settings in the body change, the answer has all of them
+async fn update_settings(
+    req: HttpRequest,
+    body: web::Json<Settings>,
+    srv: web::Data<Addr<Server>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let msg = messages::UpdateSettingsMessage {
+        settings: body.into_inner(),
+    };
+    match srv.send(msg).await {
+        Ok(Ok(settings)) => HttpResponse::Ok().json(settings),
+        Ok(Err(detail)) => failed(StatusCode::BAD_REQUEST, &detail),
+        Err(_) => unavailable(),
+    }
+}
+
+fn no_replays() -> HttpResponse {
+    failed(StatusCode::NOT_FOUND, "replays are not recorded")
+}
+
+/// the replay files in replay_dir, by room and then time
+async fn list_replays(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if let Some(res) = refuse(&req, &config) {
+        return res;
+    }
+    let dir = match &config.replay_dir {
+        Some(dir) => dir,
+        None => return no_replays(),
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return HttpResponse::Ok().json([(); 0]),
+        Err(e) => return failed(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| Replay::is_file_name(name))
+        .collect();
+    names.sort();
+    HttpResponse::Ok().json(names)
+}
+
+/// a websocket that gets the replay in snapshots at the speed it was played, like a client in the room
+async fn watch_replay(
+    req: HttpRequest,
+    stream: web::Payload,
+    file: web::Path<String>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, Error> {
+    if let Some(res) = refuse_websocket(&req, &config) {
+        return Ok(res);
+    }
+    let dir = match &config.replay_dir {
+        Some(dir) => dir,
+        None => return Ok(no_replays()),
+    };
+    let file = file.into_inner();
+    if !Replay::is_file_name(&file) {
+        return Ok(failed(StatusCode::BAD_REQUEST, "not a replay file name"));
+    }
+    // replays can be large, read them where waiting on the disk doesnt hold up other requests
+    let path = Path::new(dir).join(&file);
+    let replay = match web::block(move || Replay::load(&path)).await {
+        Ok(Ok(replay)) => replay,
+        Ok(Err(e)) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(failed(StatusCode::NOT_FOUND, "no such replay"))
+        }
+        Ok(Err(e)) => return Ok(failed(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string())),
+        Err(_) => return Ok(unavailable()),
+    };
+    let spectator = Spectator::new(Playback::new(replay), protocol::Format::negotiate(&req));
+    ws::WsResponseBuilder::new(spectator, &req, stream)
+        .protocols(&protocol::SUBPROTOCOLS)
+        .start()
+}
@@ -0,0 +1,580 @@
+//! Server settings.
+//!
+//! Every field can be set, from lowest to highest priority, in a toml file
+//! (`--config <path>` or `GAME_CONFIG=<path>`), as an env var with the field name in
+//! uppercase after `GAME_` (`GAME_PORT=8080`) or as a cli flag with dashes (`--port 8080`).
+
+use crate::logging::LogFormat;
+use crate::server::OverflowPolicy;
+use crate::session::Penalty;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// http worker threads
+    pub workers: usize,
+    /// threads the rooms are spread over, 0 means one per cpu
+    pub room_threads: usize,
+    pub tick_ms: u64,
+    pub heartbeat_interval_ms: u64,
+    pub client_timeout_ms: u64,
+    /// players spawn in -spawn_half_extent..spawn_half_extent on x and z
+    pub spawn_half_extent: f32,
+    /// players fall off outside -arena_half_extent..arena_half_extent on x and z
+    pub arena_half_extent: f32,
+    pub input_queue_len: usize,
+    pub input_catch_up_len: usize,
+    pub input_overflow: OverflowPolicy,
+    /// how far back hit detection may rewind victims for a laggy attacker
+    pub max_rewind_ms: u64,
+    /// how long the player of a dropped connection stays in the world waiting for
+    /// the client to resume, 0 removes it right away
+    pub resume_grace_ms: u64,
+    /// bad messages a client may send within any strike_window_ms, one more disconnects it
+    pub max_strikes: u32,
+    pub strike_window_ms: u64,
+    /// inputs a player may send per second, about two per tick leaves room for bursts.
+    /// a game rule checked by the room: inputs over it are dropped and the audit log notes it
+    pub max_inputs_per_second: u32,
+    /// holding an attack longer than this goes in the audit log
+    pub max_attack_hold_ms: u64,
+    /// file suspicious client behaviour is appended to, stdout if not set
+    pub audit_log: Option<String>,
+    /// bigger websocket frames close the connection
+    pub max_message_bytes: usize,
+    /// in chars
+    pub max_chat_len: usize,
+    /// per session token buckets: refill per second and how many can be sent at once
+    pub chat_per_second: f64,
+    pub chat_burst: f64,
+    /// join, list, name and time
+    pub commands_per_second: f64,
+    pub commands_burst: f64,
+    /// input and ack frames together, against floods, so well above max_inputs_per_second
+    /// plus one ack per snapshot
+    pub inputs_per_second: f64,
+    pub inputs_burst: f64,
+    /// what happens to a client that goes over one of the limits above
+    pub rate_limit_penalty: Penalty,
+    /// how long the mute penalty ignores chat
+    pub mute_ms: u64,
+    /// chat messages per room replayed to players joining it
+    pub chat_history_len: usize,
+    /// words replaced in chat, `word` for stars or `word=replacement`. comma separated in env and flags
+    pub word_filter: Vec<String>,
+    /// json file bans are kept in
+    pub bans_file: String,
+    /// reverse proxies in front of the server. for connections from them the client address is
+    /// taken from X-Forwarded-For, so bans hit the player and not the proxy. comma separated in
+    /// env and flags
+    pub trusted_proxies: Vec<IpAddr>,
+    /// clients that send it in a moderator message can mute, kick and ban, not set disables that
+    pub moderator_token: Option<String>,
+    /// bearer token for the /admin api, not set disables it
+    pub admin_token: Option<String>,
+    /// which logs are written, in env filter syntax like `info,websocketgameserver::session=debug`
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    /// /readyz fails when a room hasnt ticked for this long
+    pub max_tick_stall_ms: u64,
+    /// how long clients are told to wait before reconnecting when the server shuts down
+    pub shutdown_reconnect_ms: u64,
+    /// every room records a replay file in here, not set records nothing
+    pub replay_dir: Option<String>,
+    /// rooms seed the rng of their simulation from this and their name, so a room plays
+    /// out the same for the same inputs every run. not set picks random seeds
+    pub seed: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: String::from("0.0.0.0"),
+            port: 8080,
+            workers: 2,
+            room_threads: 0,
+            tick_ms: 17,
+            heartbeat_interval_ms: 1000,
+            client_timeout_ms: 10000,
+            spawn_half_extent: 8.0,
+            arena_half_extent: 10.0,
+            input_queue_len: 8,
+            input_catch_up_len: 4,
+            input_overflow: OverflowPolicy::DropOldest,
+            max_rewind_ms: 200,
+            resume_grace_ms: 30000,
+            max_strikes: 5,
+            strike_window_ms: 60000,
+            max_inputs_per_second: 120,
+            max_attack_hold_ms: 5000,
+            audit_log: None,
+            max_message_bytes: 4096,
+            max_chat_len: 200,
+            chat_per_second: 1.0,
+            chat_burst: 5.0,
+            commands_per_second: 5.0,
+            commands_burst: 10.0,
+            inputs_per_second: 240.0,
+            inputs_burst: 60.0,
+            rate_limit_penalty: Penalty::Drop,
+            mute_ms: 30000,
+            chat_history_len: 50,
+            word_filter: Vec::new(),
+            bans_file: String::from("bans.json"),
+            trusted_proxies: Vec::new(),
+            moderator_token: None,
+            admin_token: None,
+            log_filter: String::from("info"),
+            log_format: LogFormat::Text,
+            max_tick_stall_ms: 2000,
+            shutdown_reconnect_ms: 5000,
+            replay_dir: None,
+            seed: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "config error: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(e: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+fn err<T>(msg: impl Into<String>) -> Result<T, ConfigError> {
+    Err(ConfigError(msg.into()))
+}
+
+impl Config {
+    /// toml file, then env vars, then cli flags, then validate
+    pub fn load() -> Result<Self, ConfigError> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let flags = parse_flags(&args)?;
+
+        let path = flags
+            .iter()
+            .find(|(k, _)| k == "config")
+            .map(|(_, v)| v.clone())
+            .or_else(|| std::env::var(env_var("config")).ok());
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+
+        for key in Self::keys() {
+            if let Ok(value) = std::env::var(env_var(key)) {
+                config.set(key, &value)?;
+            }
+        }
+        for (key, value) in flags.iter().filter(|(k, _)| k != "config") {
+            config.set(&key.replace('-', "_"), value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return err(format!("cant read {path}: {e}")),
+        };
+        toml::from_str(&text).or_else(|e| err(format!("{path}: {e}")))
+    }
+
+    /// every setting the env vars and flags can reach, by name
+    fn keys() -> impl Iterator<Item = &'static str> {
+        SETTERS.iter().map(|(key, _)| *key)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match SETTERS.iter().find(|(k, _)| *k == key) {
+            Some((key, set)) => set(self, key, value),
+            None => err(format!("unknown setting {key}")),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return err("port must not be 0");
+        }
+        if self.workers == 0 {
+            return err("workers must be at least 1");
+        }
+        if !(1..=1000).contains(&self.tick_ms) {
+            return err("tick_ms must be in 1..=1000");
+        }
+        if self.heartbeat_interval_ms == 0 || self.heartbeat_interval_ms >= self.client_timeout_ms {
+            return err("heartbeat_interval_ms must be above 0 and below client_timeout_ms");
+        }
+        if !(self.spawn_half_extent > 0.0 && self.spawn_half_extent <= self.arena_half_extent) {
+            return err("spawn_half_extent must be above 0 and at most arena_half_extent");
+        }
+        if !self.arena_half_extent.is_finite() {
+            return err("arena_half_extent must be finite");
+        }
+        if self.input_queue_len == 0 || self.input_catch_up_len == 0 {
+            return err("input_queue_len and input_catch_up_len must be at least 1");
+        }
+        if self.max_strikes == 0 {
+            return err("max_strikes must be at least 1");
+        }
+        if self.max_inputs_per_second == 0 {
+            return err("max_inputs_per_second must be at least 1");
+        }
+        if self.max_message_bytes < 64 {
+            return err("max_message_bytes must be at least 64");
+        }
+        let buckets = [
+            (self.chat_per_second, self.chat_burst),
+            (self.commands_per_second, self.commands_burst),
+            (self.inputs_per_second, self.inputs_burst),
+        ];
+        if !buckets
+            .iter()
+            .all(|(per_second, burst)| per_second.is_finite() && *per_second > 0.0 && *burst >= 1.0)
+        {
+            return err("rate limits must be above 0 per second with a burst of at least 1");
+        }
+        let short = |token: &Option<String>| matches!(token, Some(token) if token.len() < 16);
+        if short(&self.moderator_token) || short(&self.admin_token) {
+            return err("moderator_token and admin_token must be at least 16 characters");
+        }
+        if self.max_tick_stall_ms < 2 * self.tick_ms {
+            return err("max_tick_stall_ms must be at least two ticks");
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_filter) {
+            return err(format!("log_filter: {e}"));
+        }
+        Ok(())
+    }
+
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_millis(self.client_timeout_ms)
+    }
+
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_millis(self.resume_grace_ms)
+    }
+
+    pub fn strike_window(&self) -> Duration {
+        Duration::from_millis(self.strike_window_ms)
+    }
+
+    pub fn mute(&self) -> Duration {
+        Duration::from_millis(self.mute_ms)
+    }
+
+    pub fn max_tick_stall(&self) -> Duration {
+        Duration::from_millis(self.max_tick_stall_ms)
+    }
+
+    pub fn shutdown_reconnect(&self) -> Duration {
+        Duration::from_millis(self.shutdown_reconnect_ms)
+    }
+}
+
+/// env vars are prefixed so they dont pick up shell variables like HOST
+const ENV_PREFIX: &str = "GAME_";
+
+fn env_var(key: &str) -> String {
+    format!("{ENV_PREFIX}{}", key.to_uppercase())
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .trim()
+        .parse()
+        .or_else(|_| err(format!("invalid value for {key}: {value:?}")))
+}
+
+/// sets a field from its key and the text value
+type Setter = fn(&mut Config, &str, &str) -> Result<(), ConfigError>;
+
+/// one entry per field of Config
+const SETTERS: &[(&str, Setter)] = &[
+    ("host", |c, _, v| {
+        c.host = v.to_owned();
+        Ok(())
+    }),
+    ("port", |c, k, v| parse(k, v).map(|v| c.port = v)),
+    ("workers", |c, k, v| parse(k, v).map(|v| c.workers = v)),
+    ("room_threads", |c, k, v| {
+        parse(k, v).map(|v| c.room_threads = v)
+    }),
+    ("tick_ms", |c, k, v| parse(k, v).map(|v| c.tick_ms = v)),
+    ("heartbeat_interval_ms", |c, k, v| {
+        parse(k, v).map(|v| c.heartbeat_interval_ms = v)
+    }),
+    ("client_timeout_ms", |c, k, v| {
+        parse(k, v).map(|v| c.client_timeout_ms = v)
+    }),
+    ("spawn_half_extent", |c, k, v| {
+        parse(k, v).map(|v| c.spawn_half_extent = v)
+    }),
+    ("arena_half_extent", |c, k, v| {
+        parse(k, v).map(|v| c.arena_half_extent = v)
+    }),
+    ("input_queue_len", |c, k, v| {
+        parse(k, v).map(|v| c.input_queue_len = v)
+    }),
+    ("input_catch_up_len", |c, k, v| {
+        parse(k, v).map(|v| c.input_catch_up_len = v)
+    }),
+    ("input_overflow", |c, _, v| {
+        c.input_overflow = match v.trim() {
+            "drop_oldest" => OverflowPolicy::DropOldest,
+            "merge" => OverflowPolicy::Merge,
+            _ => {
+                return err(format!(
+                    "input_overflow must be drop_oldest or merge, got {v:?}"
+                ))
+            }
+        };
+        Ok(())
+    }),
+    ("max_rewind_ms", |c, k, v| {
+        parse(k, v).map(|v| c.max_rewind_ms = v)
+    }),
+    ("resume_grace_ms", |c, k, v| {
+        parse(k, v).map(|v| c.resume_grace_ms = v)
+    }),
+    ("max_strikes", |c, k, v| {
+        parse(k, v).map(|v| c.max_strikes = v)
+    }),
+    ("strike_window_ms", |c, k, v| {
+        parse(k, v).map(|v| c.strike_window_ms = v)
+    }),
+    ("max_inputs_per_second", |c, k, v| {
+        parse(k, v).map(|v| c.max_inputs_per_second = v)
+    }),
+    ("max_attack_hold_ms", |c, k, v| {
+        parse(k, v).map(|v| c.max_attack_hold_ms = v)
+    }),
+    ("audit_log", |c, _, v| {
+        c.audit_log = Some(v.to_owned());
+        Ok(())
+    }),
+    ("max_message_bytes", |c, k, v| {
+        parse(k, v).map(|v| c.max_message_bytes = v)
+    }),
+    ("max_chat_len", |c, k, v| {
+        parse(k, v).map(|v| c.max_chat_len = v)
+    }),
+    ("chat_per_second", |c, k, v| {
+        parse(k, v).map(|v| c.chat_per_second = v)
+    }),
+    ("chat_burst", |c, k, v| {
+        parse(k, v).map(|v| c.chat_burst = v)
+    }),
+    ("commands_per_second", |c, k, v| {
+        parse(k, v).map(|v| c.commands_per_second = v)
+    }),
+    ("commands_burst", |c, k, v| {
+        parse(k, v).map(|v| c.commands_burst = v)
+    }),
+    ("inputs_per_second", |c, k, v| {
+        parse(k, v).map(|v| c.inputs_per_second = v)
+    }),
+    ("inputs_burst", |c, k, v| {
+        parse(k, v).map(|v| c.inputs_burst = v)
+    }),
+    ("rate_limit_penalty", |c, _, v| {
+        c.rate_limit_penalty = match v.trim() {
+            "drop" => Penalty::Drop,
+            "mute" => Penalty::Mute,
+            "disconnect" => Penalty::Disconnect,
+            _ => {
+                return err(format!(
+                    "rate_limit_penalty must be drop, mute or disconnect, got {v:?}"
+                ))
+            }
+        };
+        Ok(())
+    }),
+    ("mute_ms", |c, k, v| parse(k, v).map(|v| c.mute_ms = v)),
+    ("chat_history_len", |c, k, v| {
+        parse(k, v).map(|v| c.chat_history_len = v)
+    }),
+    ("word_filter", |c, _, v| {
+        c.word_filter = v
+            .split(',')
+            .map(str::trim)
+            .filter(|w| !w.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Ok(())
+    }),
+    ("bans_file", |c, _, v| {
+        c.bans_file = v.to_owned();
+        Ok(())
+    }),
+    ("trusted_proxies", |c, k, v| {
+        c.trusted_proxies = v
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| parse(k, ip))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }),
+    ("moderator_token", |c, _, v| {
+        c.moderator_token = Some(v.to_owned());
+        Ok(())
+    }),
+    ("admin_token", |c, _, v| {
+        c.admin_token = Some(v.to_owned());
+        Ok(())
+    }),
+    ("log_filter", |c, _, v| {
+        c.log_filter = v.to_owned();
+        Ok(())
+    }),
+    ("log_format", |c, _, v| {
+        c.log_format = match v.trim() {
+            "text" => LogFormat::Text,
+            "json" => LogFormat::Json,
+            _ => return err(format!("log_format must be text or json, got {v:?}")),
+        };
+        Ok(())
+    }),
+    ("max_tick_stall_ms", |c, k, v| {
+        parse(k, v).map(|v| c.max_tick_stall_ms = v)
+    }),
+    ("shutdown_reconnect_ms", |c, k, v| {
+        parse(k, v).map(|v| c.shutdown_reconnect_ms = v)
+    }),
+    ("replay_dir", |c, _, v| {
+        c.replay_dir = Some(v.to_owned());
+        Ok(())
+    }),
+    ("seed", |c, k, v| parse(k, v).map(|v| c.seed = Some(v))),
+];
+
+/// The settings that can be changed while the server runs, None leaves one as it is
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub tick_ms: Option<u64>,
+    pub spawn_half_extent: Option<f32>,
+    pub arena_half_extent: Option<f32>,
+    pub max_rewind_ms: Option<u64>,
+    pub max_inputs_per_second: Option<u32>,
+    pub max_attack_hold_ms: Option<u64>,
+}
+
+impl Config {
+    /// the current value of every runtime setting
+    pub fn settings(&self) -> Settings {
+        Settings {
+            tick_ms: Some(self.tick_ms),
+            spawn_half_extent: Some(self.spawn_half_extent),
+            arena_half_extent: Some(self.arena_half_extent),
+            max_rewind_ms: Some(self.max_rewind_ms),
+            max_inputs_per_second: Some(self.max_inputs_per_second),
+            max_attack_hold_ms: Some(self.max_attack_hold_ms),
+        }
+    }
+
+    /// a copy with the settings that are set changed, if that is still a valid config
+    pub fn with_settings(&self, settings: &Settings) -> Result<Self, ConfigError> {
+        let mut config = self.clone();
+        config.tick_ms = settings.tick_ms.unwrap_or(config.tick_ms);
+        config.spawn_half_extent = settings
+            .spawn_half_extent
+            .unwrap_or(config.spawn_half_extent);
+        config.arena_half_extent = settings
+            .arena_half_extent
+            .unwrap_or(config.arena_half_extent);
+        config.max_rewind_ms = settings.max_rewind_ms.unwrap_or(config.max_rewind_ms);
+        config.max_inputs_per_second = settings
+            .max_inputs_per_second
+            .unwrap_or(config.max_inputs_per_second);
+        config.max_attack_hold_ms = settings
+            .max_attack_hold_ms
+            .unwrap_or(config.max_attack_hold_ms);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// compare without returning early, so the time it takes doesnt give away how much of a guess was right
+pub fn same_secret(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// `--key value` and `--key=value` pairs
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return err(format!("unexpected argument {arg:?}")),
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.to_owned(), value.to_owned()),
+            None => match args.next() {
+                Some(value) => (flag.to_owned(), value.clone()),
+                None => return err(format!("missing value for --{flag}")),
+            },
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_vars_are_prefixed() {
+        assert_eq!(env_var("tick_ms"), "GAME_TICK_MS");
+        assert_eq!(env_var("host"), "GAME_HOST");
+    }
+
+    #[test]
+    fn every_key_is_set_once_and_others_are_refused() {
+        let keys: Vec<&str> = Config::keys().collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[..i].contains(key), "{key} is in the table twice");
+        }
+        let mut config = Config::default();
+        config.set("tick_ms", " 20 ").unwrap();
+        config.set("seed", "7").unwrap();
+        assert_eq!((config.tick_ms, config.seed), (20, Some(7)));
+        assert!(config.set("tick_ms", "fast").is_err());
+        assert!(config.set("TICK_MS", "20").is_err());
+        assert!(config.set("config", "server.toml").is_err());
+    }
+}
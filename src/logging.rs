@@ -0,0 +1,25 @@
+//! Leveled logs through tracing, filtered with `log_filter` (env filter syntax,
+//! e.g. `info,websocketgameserver::session=debug`) and written as text or json lines.
+
+use crate::config::Config;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    /// one json object per line, for the container
+    Json,
+}
+
+/// install the global subscriber, once at startup
+pub fn init(config: &Config) {
+    // validate() already checked the filter parses
+    let filter = EnvFilter::try_new(&config.log_filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
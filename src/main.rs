@@ -1,23 +1,108 @@
+mod admin;
+mod config;
+mod logging;
 mod messages;
+mod metrics;
+mod protocol;
 mod server;
 mod session;
+mod spectator;
 
 use actix::*;
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{
+    error, http::header, rt, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+};
 use actix_web_actors::ws;
+use config::Config;
+use serde::Deserialize;
 use session::Session;
+use std::net::IpAddr;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
+
+/// set once the server starts shutting down, new connections are turned away from then on
+struct ShuttingDown(AtomicBool);
+
+impl ShuttingDown {
+    fn get(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// how long open connections get to finish after a shutdown before they are dropped
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Deserialize)]
+struct ResumeQuery {
+    resume: Option<String>,
+}
+
+/// the peer of the connection, or for a trusted proxy the last address in X-Forwarded-For
+/// that isnt one. the client can put anything in the header, only what the proxies appended
+/// after it counts
+fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in forwarded.iter().rev() {
+        match hop.trim().parse() {
+            Ok(ip) if trusted_proxies.contains(&ip) => continue,
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+    tracing::warn!(%peer, "no client address from a trusted proxy, using the proxy's");
+    Some(peer)
+}
 
 async fn websocket_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::Server>>,
+    config: web::Data<Config>,
+    metrics: web::Data<metrics::Metrics>,
+    shutting_down: web::Data<ShuttingDown>,
 ) -> Result<HttpResponse, Error> {
+    if shutting_down.get() {
+        let retry_after = config.shutdown_reconnect().as_secs().max(1);
+        return Ok(HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, retry_after))
+            .body("server shutting down"));
+    }
     let server_addr = srv.get_ref().clone();
-    ws::start(Session::new(server_addr), &req, stream)
+    let format = protocol::Format::negotiate(&req);
+    // a client that lost its connection reconnects with /ws?resume=<token> to get its player back
+    let resume = web::Query::<ResumeQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.resume.as_deref()?.parse().ok());
+    let ip = client_ip(&req, &config.trusted_proxies);
+    let banned = server_addr
+        .send(messages::CheckBanMessage { ip })
+        .await
+        .map_err(error::ErrorServiceUnavailable)?;
+    if let Some(reason) = banned {
+        let body = match reason.is_empty() {
+            true => String::from("banned"),
+            false => format!("banned: {reason}"),
+        };
+        return Ok(HttpResponse::Forbidden().body(body));
+    }
+    let metrics = metrics.into_inner();
+    let session = Session::new(server_addr, format, resume, ip, &config, metrics);
+    ws::WsResponseBuilder::new(session, &req, stream)
+        .protocols(&protocol::SUBPROTOCOLS)
+        .frame_size(config.max_message_bytes)
+        .start()
 }
 
 async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
@@ -25,21 +110,219 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
     format!("Visitors: {current_count}")
 }
 
+async fn get_metrics(
+    srv: web::Data<Addr<server::Server>>,
+    metrics: web::Data<metrics::Metrics>,
+) -> Result<HttpResponse, Error> {
+    let sent = Instant::now();
+    let stats = srv
+        .send(messages::StatsMessage { sent })
+        .await
+        .map_err(error::ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render(&stats)))
+}
+
+/// the process is up and answering http
+async fn healthz() -> impl Responder {
+    "ok"
+}
+
+/// ready for players: not shutting down, and every room is still ticking
+async fn readyz(
+    srv: web::Data<Addr<server::Server>>,
+    config: web::Data<Config>,
+    shutting_down: web::Data<ShuttingDown>,
+) -> HttpResponse {
+    let not_ready = |detail: String| HttpResponse::ServiceUnavailable().body(detail);
+    if shutting_down.get() {
+        return not_ready(String::from("shutting down"));
+    }
+    let stall = config.max_tick_stall();
+    // the server waits up to a stall on the rooms, so give it that and a stall of its own
+    let stats = match srv
+        .send(messages::StatsMessage {
+            sent: Instant::now(),
+        })
+        .timeout(stall * 2)
+        .await
+    {
+        Ok(stats) => stats,
+        Err(_) => return not_ready(String::from("server not answering")),
+    };
+    if !stats.unresponsive.is_empty() {
+        return not_ready(format!(
+            "rooms not answering: {}",
+            stats.unresponsive.join(", ")
+        ));
+    }
+    let stalled: Vec<&str> = stats
+        .rooms
+        .iter()
+        .filter(|room| room.tick_age > stall)
+        .map(|room| room.name.as_str())
+        .collect();
+    if !stalled.is_empty() {
+        return not_ready(format!("rooms not ticking: {}", stalled.join(", ")));
+    }
+    HttpResponse::Ok().body("ready")
+}
+
+/// on SIGTERM or ctrl-c turn new connections away, tell every client to reconnect later,
+/// close their sockets and stop the http server once they are gone
+fn handle_signals(
+    srv: Addr<server::Server>,
+    http: actix_web::dev::ServerHandle,
+    shutting_down: Arc<ShuttingDown>,
+    reconnect_in: Duration,
+) {
+    let shut_down = move || {
+        let (srv, http, shutting_down) = (srv.clone(), http.clone(), shutting_down.clone());
+        async move {
+            if shutting_down.0.swap(true, Ordering::SeqCst) {
+                return;
+            }
+            let _ = srv.send(messages::ShutdownMessage { reconnect_in }).await;
+            http.stop(true).await;
+        }
+    };
+    #[cfg(unix)]
+    {
+        let shut_down = shut_down.clone();
+        rt::spawn(async move {
+            use rt::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut term) => {
+                    term.recv().await;
+                    shut_down().await;
+                }
+                Err(e) => tracing::error!(error = %e, "cant listen for SIGTERM"),
+            }
+        });
+    }
+    rt::spawn(async move {
+        if rt::signal::ctrl_c().await.is_ok() {
+            shut_down().await;
+        }
+    });
+}
+
+/// simulate a recorded replay again and print a json snapshot per tick
+fn print_replay(path: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let replay = server::Replay::load(std::path::Path::new(path))?;
+    let mut playback = server::Playback::new(replay);
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    while let Some(snapshot) = playback.next_tick() {
+        serde_json::to_writer(&mut out, &snapshot)?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    println!("main running");
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("replay") {
+        return match args.next() {
+            Some(path) => print_replay(&path),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "usage: websocketgameserver replay <file>",
+            )),
+        };
+    }
+    let config = Config::load()?;
+    logging::init(&config);
     let app_state = Arc::new(AtomicUsize::new(0));
-    let server_addr = server::Server::new(app_state.clone()).start();
+    let audit = server::AuditLog::open(config.audit_log.as_deref())?;
+    let bans = server::BanList::load(&config.bans_file)?;
+    let metrics = Arc::new(metrics::Metrics::new(config.tick_duration()));
+    let server_addr =
+        server::Server::new(app_state.clone(), &config, audit, bans, metrics.clone()).start();
+    let bind = (config.host.clone(), config.port);
+    tracing::info!(host = %config.host, port = config.port, "listening");
+    let workers = config.workers;
+    let reconnect_in = config.shutdown_reconnect();
+    let config = web::Data::new(config);
+    let shutting_down = Arc::new(ShuttingDown(AtomicBool::new(false)));
 
-    HttpServer::new(move || {
+    let srv = server_addr.clone();
+    let flag = shutting_down.clone();
+    let http = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(app_state.clone()))
             .app_data(web::Data::new(server_addr.clone()))
+            .app_data(config.clone())
+            .app_data(web::Data::from(metrics.clone()))
+            .app_data(web::Data::from(flag.clone()))
             .route("/count", web::get().to(get_count))
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
+            .configure(admin::routes)
             .route("/ws", web::get().to(websocket_route))
     })
-    .workers(2)
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    .workers(workers)
+    // signals are handled below, so the clients hear about it before the sockets close
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_SECS)
+    .bind(bind)?
+    .run();
+    handle_signals(srv, http.handle(), shutting_down, reconnect_in);
+    http.await?;
+    tracing::info!("stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client_ip;
+    use actix_web::{test::TestRequest, HttpRequest};
+    use std::net::IpAddr;
+
+    const PROXY: [u8; 4] = [10, 0, 0, 2];
+
+    fn request(peer: [u8; 4], forwarded: Option<&str>) -> HttpRequest {
+        let peer = std::net::SocketAddr::from((peer, 40000));
+        let req = TestRequest::default().peer_addr(peer);
+        match forwarded {
+            Some(forwarded) => req.insert_header(("X-Forwarded-For", forwarded)),
+            None => req,
+        }
+        .to_http_request()
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn the_header_only_counts_from_a_trusted_proxy() {
+        let trusted = [IpAddr::from(PROXY)];
+        let direct = request([203, 0, 113, 7], Some("198.51.100.1"));
+        assert_eq!(client_ip(&direct, &trusted), ip("203.0.113.7"));
+        let proxied = request(PROXY, Some("198.51.100.1"));
+        assert_eq!(client_ip(&proxied, &[]), ip("10.0.0.2"));
+        assert_eq!(client_ip(&proxied, &trusted), ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn what_the_client_put_in_the_header_is_skipped() {
+        let trusted = [IpAddr::from(PROXY), ip("10.0.0.3").unwrap()];
+        // the client claims to be 1.2.3.4, the proxies appended what they saw after it
+        let req = request(PROXY, Some("1.2.3.4, 198.51.100.1, 10.0.0.3"));
+        assert_eq!(client_ip(&req, &trusted), ip("198.51.100.1"));
+        let req = request(PROXY, Some("not an address, 2001:db8::1"));
+        assert_eq!(client_ip(&req, &trusted), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn a_trusted_proxy_without_the_header_is_the_client() {
+        let trusted = [IpAddr::from(PROXY)];
+        assert_eq!(client_ip(&request(PROXY, None), &trusted), ip("10.0.0.2"));
+        let garbled = request(PROXY, Some("198.51.100.1, garbage"));
+        assert_eq!(client_ip(&garbled, &trusted), ip("10.0.0.2"));
+    }
 }
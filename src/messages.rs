@@ -1,58 +1,483 @@
-use actix::prelude::{Message, Recipient};
+use crate::config::Settings;
+use crate::metrics::{Disconnect, RoomStats, Stats};
+use crate::protocol::Snapshot;
+pub use crate::server::PlayerInput;
+use crate::server::{NameError, Player, Room, RoomConfig, SessionToken};
+use crate::session::Session;
+use actix::prelude::{Addr, Message, Recipient};
+use actix_web_actors::ws::CloseCode;
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+/// Everything a client can send in a text frame, as json tagged with its kind,
+/// e.g. `{"kind":"chat","text":"hi"}`. The first message must be a hello.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClientMessage {
+    /// the protocol version the client speaks, must match protocol::VERSION
+    Hello {
+        version: u8,
+    },
+    Input(PlayerInputWithoutId),
+    /// tick of the last snapshot received
+    Ack {
+        tick: u64,
+    },
+    /// clock sync, client_time is echoed back
+    Time {
+        client_time: f64,
+    },
+    /// to everyone in the same room
+    Chat {
+        text: String,
+    },
+    /// to the player in the same room with that name
+    Whisper {
+        to: String,
+        text: String,
+    },
+    Join {
+        room: String,
+    },
+    Name {
+        name: String,
+    },
+    List,
+    /// unlocks the moderator commands below, token is moderator_token from the config
+    Moderator {
+        token: String,
+    },
+    /// ignore chat from the player for secs
+    Mute {
+        player_id: usize,
+        secs: u64,
+    },
+    Unmute {
+        player_id: usize,
+    },
+    /// disconnect the player, it cant resume
+    Kick {
+        player_id: usize,
+        #[serde(default)]
+        reason: String,
+    },
+    /// kick the player and keep the address it connected from out, for secs or for good.
+    /// ip false is refused, without the address a ban is no more than a kick
+    Ban {
+        player_id: usize,
+        #[serde(default)]
+        reason: String,
+        secs: Option<u64>,
+        #[serde(default = "yes")]
+        ip: bool,
+    },
+}
+
+fn yes() -> bool {
+    true
+}
+
+impl ClientMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Hello { .. } => "hello",
+            ClientMessage::Input(_) => "input",
+            ClientMessage::Ack { .. } => "ack",
+            ClientMessage::Time { .. } => "time",
+            ClientMessage::Chat { .. } => "chat",
+            ClientMessage::Whisper { .. } => "whisper",
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::Name { .. } => "name",
+            ClientMessage::List => "list",
+            ClientMessage::Moderator { .. } => "moderator",
+            ClientMessage::Mute { .. } => "mute",
+            ClientMessage::Unmute { .. } => "unmute",
+            ClientMessage::Kick { .. } => "kick",
+            ClientMessage::Ban { .. } => "ban",
+        }
+    }
+}
+
+/// Everything the server sends in a text frame, except json snapshots
+#[derive(Clone, Debug, Message, Serialize)]
+#[rtype(result = "()")]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// sent on connect. resume_token reconnects to this player with /ws?resume=<token>
+    Welcome {
+        version: u8,
+        player_id: usize,
+        resume_token: String,
+        resumed: bool,
+    },
+    Rooms {
+        rooms: Vec<String>,
+    },
+    Joined {
+        room: String,
+    },
+    /// chat from a player in the same room
+    Chat {
+        id: usize,
+        name: String,
+        text: String,
+    },
+    /// sent to both the player it is to and the one it is from
+    Whisper {
+        id: usize,
+        name: String,
+        to: String,
+        text: String,
+    },
+    /// from the server to everyone in the room, like someone joining
+    System {
+        text: String,
+    },
+    /// the server is going down, the connection closes right after this. reconnecting
+    /// after reconnect_in_ms should reach the server that replaces it
+    Shutdown {
+        reconnect_in_ms: u64,
+    },
+    /// server_tick is fractional, rtt_ms and jitter_ms are what the server measured
+    Time {
+        client_time: f64,
+        server_tick: f64,
+        rtt_ms: u64,
+        jitter_ms: u64,
+    },
+    /// something the client sent was not accepted. rejected_kind is the kind of the
+    /// offending message if it got that far, "binary" for binary frames
+    Error {
+        code: ErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rejected_kind: Option<String>,
+        detail: String,
+    },
+}
+
+/// Why a client message was not accepted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// not json, or not shaped like any ClientMessage
+    BadMessage,
+    /// a binary frame that is not input or ack, or of another protocol version
+    BadFrame,
+    /// the first message has to be a hello
+    HelloRequired,
+    UnsupportedVersion,
+    /// well formed but with a value that makes no sense, like an empty room name
+    InvalidArgument,
+    /// the server could not move the player to the room
+    JoinFailed,
+    /// nobody by that name in the room
+    NoSuchPlayer,
+    /// too long, too short, odd characters or reserved
+    InvalidName,
+    /// someone in the room already goes by that name
+    NameTaken,
+    /// too many messages of this kind, try again later
+    RateLimited,
+    /// chat is ignored for a while, after going over the chat rate limit or by a moderator
+    Muted,
+    /// moderator commands need a moderator message with the right token first
+    NotModerator,
+}
+
+/// the room state of one tick, each session encodes it in the format its client asked for.
+/// baseline is the last snapshot the client acked, if the room still has it
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct GameStateMessage(pub String);
+pub struct SnapshotMessage {
+    pub snapshot: Arc<Snapshot>,
+    pub baseline: Option<Arc<Snapshot>>,
+}
+
+/// the client received the snapshot of this tick
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SnapshotAckMessage {
+    pub id: usize,
+    pub tick: u64,
+    /// the session that sent it, late acks from a connection that was replaced are dropped
+    pub addr: Recipient<SnapshotMessage>,
+}
 
+/// resume is the token of an earlier session whose player this one wants to take back.
+/// result is None if the server is full
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "Option<Connected>")]
 pub struct PlayerConnectMessage {
-    pub addr: Recipient<GameStateMessage>,
+    pub addr: Addr<Session>,
+    pub resume: Option<SessionToken>,
+    pub ip: Option<IpAddr>,
+}
+
+/// id is the public player id, token is the secret the session identifies itself with
+/// and resumes with. resumed is false if a new player was made
+pub struct Connected {
+    pub id: usize,
+    pub token: SessionToken,
+    pub room: Addr<Room>,
+    pub resumed: bool,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct PlayerDisconnectMessage {
-    pub id: usize,
+    pub token: SessionToken,
 }
 
 #[derive(Message)]
 #[rtype(result = "Vec<String>")]
 pub struct ListRooms;
 
+/// result is the room the session should send its input to from now on, or why it cant join
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<Addr<Room>, String>")]
 pub struct PlayerJoinRoomMessage {
+    pub token: SessionToken,
+    pub name: String,
+}
+
+/// hand a player entity (and the session controlling it) to a room, the room picks where
+/// it spawns. None for a player that just connected
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AddPlayerMessage {
     pub id: usize,
     pub name: String,
+    pub addr: Recipient<SnapshotMessage>,
+    pub player: Option<Player>,
 }
 
+/// the player changed its display name
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct ChatMessage {
+pub struct PlayerNameMessage {
+    pub id: usize,
+    pub name: String,
+}
+
+/// the connection of a player dropped, leave the entity standing without a session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DetachSessionMessage {
     pub id: usize,
+}
+
+/// a session resumed a detached player
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AttachSessionMessage {
+    pub id: usize,
+    pub addr: Recipient<SnapshotMessage>,
+}
+
+/// tell a session to close its websocket
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSessionMessage {
+    pub why: Disconnect,
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// take a player entity out of a room
+#[derive(Message)]
+#[rtype(result = "Option<Player>")]
+pub struct RemovePlayerMessage {
+    pub id: usize,
+}
+
+/// smoothed round trip time of a session, measured with pings
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PlayerPingMessage {
+    pub id: usize,
+    pub rtt_ms: u16,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseRoomMessage;
+
+/// write out what the room still has buffered, like the end of its replay
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FlushRoomMessage;
+
+/// say something to the room the player is in
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChatMessage {
+    pub token: SessionToken,
     pub text: String,
 }
 
-//the player_input sent from client
-#[derive(Deserialize)]
-pub struct PlayerInputWithoutId {
-    pub step_forward: bool,
-    pub step_backward: bool,
-    pub step_left: bool,
-    pub step_right: bool,
-    pub kick: bool,
-    pub punch: bool,
-    pub run: bool,
-    pub facing_rad: f32,
+/// say something to one player in the same room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WhisperMessage {
+    pub token: SessionToken,
+    pub to: String,
+    pub text: String,
+}
+
+/// the display name the player goes by, checked and made unique in its room by the server
+#[derive(Message)]
+#[rtype(result = "Result<(), NameError>")]
+pub struct SetNameMessage {
+    pub token: SessionToken,
+    pub name: String,
+}
+
+/// what a moderator can do to a player
+#[derive(Debug)]
+pub enum ModAction {
+    Mute(Duration),
+    Unmute,
+    Kick {
+        reason: String,
+    },
+    /// duration None is for good
+    Ban {
+        reason: String,
+        duration: Option<Duration>,
+        ip: bool,
+    },
+}
+
+/// by says who did it, for the audit log. result is what was done or why it wasnt
+#[derive(Message)]
+#[rtype(result = "Result<String, String>")]
+pub struct ModerateMessage {
+    pub by: String,
+    pub player_id: usize,
+    pub action: ModAction,
+}
+
+/// result is the reason, if the address is banned
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct CheckBanMessage {
+    pub ip: Option<IpAddr>,
 }
 
-#[derive(Message, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+/// the room the player is in changed without the client asking, like when its room was closed
+#[derive(Message)]
 #[rtype(result = "()")]
-pub struct PlayerInput {
-    /// Id of the client session
+pub struct RoomChangedMessage {
+    pub room: String,
+    pub addr: Addr<Room>,
+}
+
+/// the rooms tick rate changed, sessions estimate the server tick with it
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TickDurationMessage {
+    pub tick_duration: Duration,
+}
+
+/// every room with the players in it, for the admin api
+#[derive(Message)]
+#[rtype(result = "Vec<RoomInfo>")]
+pub struct ListRoomInfo;
+
+/// what a room looks like right now
+#[derive(Message)]
+#[rtype(result = "RoomInfo")]
+pub struct RoomInfoMessage;
+
+#[derive(Debug, Serialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub tick: u64,
+    pub players: Vec<PlayerInfo>,
+}
+
+/// connected is false while the player waits for its client to resume
+#[derive(Debug, Serialize)]
+pub struct PlayerInfo {
     pub id: usize,
+    pub name: String,
+    pub pos: [f32; 3],
+    pub health: f32,
+    pub ping_ms: u16,
+    pub connected: bool,
+}
+
+/// a system message to one room, or to every room if room is None.
+/// result is how many rooms it went to
+#[derive(Message)]
+#[rtype(result = "Result<usize, String>")]
+pub struct AnnounceMessage {
+    pub room: Option<String>,
+    pub text: String,
+}
+
+/// start a room nobody is in yet, false if it already exists
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CreateRoomMessage {
+    pub name: String,
+}
+
+/// stop a room, moving the players in it to the default room
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct ShutRoomMessage {
+    pub name: String,
+}
+
+/// the runtime settings as they are now
+#[derive(Message)]
+#[rtype(result = "Settings")]
+pub struct GetSettingsMessage;
+
+/// change runtime settings of every room, result is all of them after the change
+#[derive(Message)]
+#[rtype(result = "Result<Settings, String>")]
+pub struct UpdateSettingsMessage {
+    pub settings: Settings,
+}
+
+/// settings a room runs with, sent to every room when they change
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RoomConfigMessage {
+    pub config: RoomConfig,
+}
+
+/// sessions and mailboxes of every room for /metrics. sent is when the scrape asked,
+/// so the time the message waited can be measured
+#[derive(Message)]
+#[rtype(result = "Stats")]
+pub struct StatsMessage {
+    pub sent: Instant,
+}
+
+#[derive(Message)]
+#[rtype(result = "RoomStats")]
+pub struct RoomStatsMessage {
+    pub sent: Instant,
+}
+
+/// tell every client the server is going down, close their connections, save the bans
+/// and have the rooms write out their replays
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ShutdownMessage {
+    pub reconnect_in: Duration,
+}
+
+//the player_input sent from client
+#[derive(Debug, Deserialize)]
+pub struct PlayerInputWithoutId {
+    #[serde(default)]
+    pub seq: u32,
     pub step_forward: bool,
     pub step_backward: bool,
     pub step_left: bool,
@@ -63,18 +488,6 @@ pub struct PlayerInput {
     pub facing_rad: f32,
 }
 
-impl PlayerInput {
-    pub fn new() -> Self {
-        Self {
-            id: 0,
-            step_forward: false,
-            step_backward: false,
-            step_left: false,
-            step_right: false,
-            kick: false,
-            punch: false,
-            run: false,
-            facing_rad: 0.,
-        }
-    }
+impl Message for PlayerInput {
+    type Result = ();
 }
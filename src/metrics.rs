@@ -0,0 +1,351 @@
+//! Counters for `/metrics`, in the prometheus text format.
+//!
+//! Everything is an atomic so rooms and sessions on any thread can update them without
+//! going through an actor. Per second rates are left to prometheus (`rate(...)`).
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Why a websocket connection ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// the client sent a close frame
+    ClientClosed,
+    /// the connection ended without a close frame
+    Dropped,
+    HeartbeatTimeout,
+    ProtocolError,
+    /// too many bad messages, or an unsupported protocol version
+    BadInput,
+    RateLimited,
+    TooBig,
+    Kicked,
+    /// the player was resumed from another connection
+    Replaced,
+    ServerFull,
+    ServerError,
+    /// the server is shutting down
+    Shutdown,
+}
+
+impl Disconnect {
+    const ALL: [Disconnect; 12] = [
+        Disconnect::ClientClosed,
+        Disconnect::Dropped,
+        Disconnect::HeartbeatTimeout,
+        Disconnect::ProtocolError,
+        Disconnect::BadInput,
+        Disconnect::RateLimited,
+        Disconnect::TooBig,
+        Disconnect::Kicked,
+        Disconnect::Replaced,
+        Disconnect::ServerFull,
+        Disconnect::ServerError,
+        Disconnect::Shutdown,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Disconnect::ClientClosed => "client_closed",
+            Disconnect::Dropped => "dropped",
+            Disconnect::HeartbeatTimeout => "heartbeat_timeout",
+            Disconnect::ProtocolError => "protocol_error",
+            Disconnect::BadInput => "bad_input",
+            Disconnect::RateLimited => "rate_limited",
+            Disconnect::TooBig => "too_big",
+            Disconnect::Kicked => "kicked",
+            Disconnect::Replaced => "replaced",
+            Disconnect::ServerFull => "server_full",
+            Disconnect::ServerError => "server_error",
+            Disconnect::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Cumulative buckets with fixed upper bounds, like a prometheus histogram
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    /// f64 bits
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: Vec<f64>) -> Self {
+        Self {
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let n = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {n}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = f64::from_bits(self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+/// What the actors look like when /metrics is scraped
+#[derive(Debug)]
+pub struct Stats {
+    /// how long the scrape waited in the Server mailbox
+    pub server_wait: Duration,
+    pub rooms: Vec<RoomStats>,
+    /// rooms that didnt answer in time, their thread is probably stuck
+    pub unresponsive: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct RoomStats {
+    pub name: String,
+    pub sessions: usize,
+    pub players: usize,
+    /// how long the scrape waited in the room mailbox, behind inputs and acks
+    pub mailbox_wait: Duration,
+    /// since the room last simulated a tick
+    pub tick_age: Duration,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub tick_seconds: Histogram,
+    /// ticks that took longer than the tick duration
+    pub tick_overruns: AtomicU64,
+    /// ticks given up on because a room fell too far behind
+    pub ticks_skipped: AtomicU64,
+    pub snapshot_bytes: Histogram,
+    pub text_in: AtomicU64,
+    pub binary_in: AtomicU64,
+    pub text_out: AtomicU64,
+    pub snapshots_out: AtomicU64,
+    disconnects: [AtomicU64; Disconnect::ALL.len()],
+}
+
+/// upper bounds of the tick duration buckets, as fractions of the tick
+const TICK_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 0.75, 1.0, 1.5, 3.0,
+];
+
+/// add one to a counter
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// tick duration buckets are spread around the tick the server started with
+    pub fn new(tick_duration: Duration) -> Self {
+        let tick = tick_duration.as_secs_f64();
+        Self {
+            // whole microseconds, so the labels dont come out as 8.500000000000001e-5
+            tick_seconds: Histogram::new(
+                TICK_BUCKETS
+                    .iter()
+                    .map(|f| (f * tick * 1e6).round() / 1e6)
+                    .collect(),
+            ),
+            tick_overruns: AtomicU64::new(0),
+            ticks_skipped: AtomicU64::new(0),
+            snapshot_bytes: Histogram::new(vec![
+                64.0, 128.0, 256.0, 512.0, 1024.0, 2048.0, 4096.0, 8192.0, 16384.0,
+            ]),
+            text_in: AtomicU64::new(0),
+            binary_in: AtomicU64::new(0),
+            text_out: AtomicU64::new(0),
+            snapshots_out: AtomicU64::new(0),
+            disconnects: Default::default(),
+        }
+    }
+
+    pub fn disconnect(&self, why: Disconnect) {
+        let i = Disconnect::ALL.iter().position(|d| *d == why).unwrap_or(0);
+        inc(&self.disconnects[i]);
+    }
+
+    pub fn render(&self, stats: &Stats) -> String {
+        let mut out = String::with_capacity(4096);
+        let o = &mut out;
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+
+        self.tick_seconds.render(
+            o,
+            "game_tick_duration_seconds",
+            "Time spent simulating one tick.",
+        );
+        let name = "game_tick_overruns_total";
+        header(
+            o,
+            name,
+            "counter",
+            "Ticks that took longer than the tick duration.",
+        );
+        let _ = writeln!(o, "{name} {}", load(&self.tick_overruns));
+        let name = "game_ticks_skipped_total";
+        header(
+            o,
+            name,
+            "counter",
+            "Ticks skipped by rooms that fell too far behind.",
+        );
+        let _ = writeln!(o, "{name} {}", load(&self.ticks_skipped));
+        self.snapshot_bytes.render(
+            o,
+            "game_snapshot_bytes",
+            "Size of encoded snapshots sent to clients.",
+        );
+
+        let name = "game_messages_received_total";
+        header(
+            o,
+            name,
+            "counter",
+            "Websocket messages received from clients.",
+        );
+        for (kind, n) in [("text", &self.text_in), ("binary", &self.binary_in)] {
+            let _ = writeln!(o, "{name}{{kind=\"{kind}\"}} {}", load(n));
+        }
+        let name = "game_messages_sent_total";
+        header(o, name, "counter", "Websocket messages sent to clients.");
+        for (kind, n) in [("text", &self.text_out), ("snapshot", &self.snapshots_out)] {
+            let _ = writeln!(o, "{name}{{kind=\"{kind}\"}} {}", load(n));
+        }
+        let name = "game_disconnects_total";
+        header(
+            o,
+            name,
+            "counter",
+            "Closed websocket connections by reason.",
+        );
+        for (why, n) in Disconnect::ALL.iter().zip(&self.disconnects) {
+            let _ = writeln!(o, "{name}{{reason=\"{}\"}} {}", why.label(), load(n));
+        }
+
+        let name = "game_room_sessions";
+        header(o, name, "gauge", "Connected sessions in each room.");
+        for room in &stats.rooms {
+            let room_name = escape(&room.name);
+            let _ = writeln!(o, "{name}{{room=\"{room_name}\"}} {}", room.sessions);
+        }
+        let name = "game_room_players";
+        let help = "Players in each room, including ones waiting for a resume.";
+        header(o, name, "gauge", help);
+        for room in &stats.rooms {
+            let room_name = escape(&room.name);
+            let _ = writeln!(o, "{name}{{room=\"{room_name}\"}} {}", room.players);
+        }
+        let name = "game_room_tick_age_seconds";
+        header(
+            o,
+            name,
+            "gauge",
+            "Time since each room last simulated a tick.",
+        );
+        for room in &stats.rooms {
+            let (room_name, age) = (escape(&room.name), room.tick_age.as_secs_f64());
+            let _ = writeln!(o, "{name}{{room=\"{room_name}\"}} {age}");
+        }
+        let name = "game_rooms_unresponsive";
+        header(
+            o,
+            name,
+            "gauge",
+            "Rooms that didnt answer the scrape in time.",
+        );
+        let _ = writeln!(o, "{name} {}", stats.unresponsive.len());
+        let name = "game_mailbox_wait_seconds";
+        let help =
+            "How long a message waited in an actor mailbox at scrape time, grows with the backlog.";
+        header(o, name, "gauge", help);
+        let wait = stats.server_wait.as_secs_f64();
+        let _ = writeln!(o, "{name}{{actor=\"server\"}} {wait}");
+        for room in &stats.rooms {
+            let (room_name, wait) = (escape(&room.name), room.mailbox_wait.as_secs_f64());
+            let _ = writeln!(o, "{name}{{actor=\"room\",room=\"{room_name}\"}} {wait}");
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// room names come from clients, so quotes and backslashes in label values are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket_labels(tick_ms: u64) -> Vec<String> {
+        let metrics = Metrics::new(Duration::from_millis(tick_ms));
+        let mut out = String::new();
+        metrics.tick_seconds.render(&mut out, "t", "");
+        out.lines()
+            .filter_map(|line| {
+                Some(
+                    line.strip_prefix("t_bucket{le=\"")?
+                        .split('"')
+                        .next()?
+                        .to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tick_buckets_follow_the_tick_duration() {
+        let labels = bucket_labels(17);
+        assert_eq!(labels.first().map(String::as_str), Some("0.000085"));
+        assert!(labels.contains(&String::from("0.017")));
+        assert_eq!(labels.last().map(String::as_str), Some("+Inf"));
+
+        let labels = bucket_labels(50);
+        assert!(labels.contains(&String::from("0.05")));
+        assert!(labels.contains(&String::from("0.15")));
+    }
+
+    #[test]
+    fn observations_land_in_every_bucket_above_them() {
+        let metrics = Metrics::new(Duration::from_millis(20));
+        metrics.tick_seconds.observe(0.019);
+        metrics.tick_seconds.observe(0.021);
+        let mut out = String::new();
+        metrics.tick_seconds.render(&mut out, "t", "");
+        assert!(out.contains("t_bucket{le=\"0.015\"} 0\n"));
+        assert!(out.contains("t_bucket{le=\"0.02\"} 1\n"));
+        assert!(out.contains("t_bucket{le=\"0.03\"} 2\n"));
+        assert!(out.contains("t_count 2\n"));
+    }
+}
@@ -0,0 +1,1076 @@
+//! Wire format of what the server sends every tick.
+//!
+//! The client picks the format with the websocket subprotocol at connect time:
+//! `game.bin.v7` for the compact binary snapshots, `game.json` (or no subprotocol) for
+//! json snapshots which are easier to read when debugging.
+//!
+//! Everything else goes in json text frames tagged with their kind, see
+//! `messages::ClientMessage` and `messages::ServerMessage`. The server sends a welcome with
+//! its protocol version on connect, and the first thing a client sends must be
+//! `{"kind":"hello","version":VERSION}`. Until then every other frame gets a
+//! `hello_required` error and costs a strike, and a hello with another version closes the
+//! connection.
+//!
+//! Every snapshot is stamped with the room tick. Clients ack the last tick they received
+//! and from then on get deltas against that baseline, or a full keyframe when the server
+//! no longer has the baseline. Players missing from a delta did not change, except
+//! `anim_ticks` which the client advances by one per tick as long as the animation is
+//! unchanged. `anim_ticks` stops at 65535 (about 18 minutes), the client stops counting there
+//! too.
+//!
+//! Binary frames are little endian. Full snapshot:
+//! ```text
+//! u8 version, u8 kind=0, u64 tick, u16 n_players, then per player:
+//!   u16 id
+//!   u32 last_input_seq (seq of the last input the server applied for this player)
+//!   u16 ping_ms (smoothed round trip time of the player)
+//!   3 x i16 pos (quantized, see POS_RANGE)
+//!   4 x i16 quat (quantized, each component in -1..1)
+//!   u8 health, u8 anim_target_id, u16 anim_ticks, u8 flags
+//!   if flags & HAS_PROJECTILE: 3 x i16 pos, 4 x i16 quat
+//!   u8 name_len, name_len bytes utf8 display name
+//! ```
+//!
+//! Delta snapshot:
+//! ```text
+//! u8 version, u8 kind=2, u64 tick, u64 baseline_tick
+//! u16 n_removed, then n_removed x u16 id
+//! u16 n_changed, then per player:
+//!   u16 id, u16 fields, followed by the fields present, in this order:
+//!   POS: 3 x i16, QUAT: 4 x i16, HEALTH: u8, ANIM: u8 anim_target_id + u16 anim_ticks,
+//!   FLAGS: u8 flags, PROJECTILE: 3 x i16 pos + 4 x i16 quat (only if flags has HAS_PROJECTILE)
+//!   INPUT_SEQ: u32 last_input_seq, PING: u16 ping_ms, NAME: u8 name_len + name_len bytes
+//!   PROJECTILE is always sent together with FLAGS, HAS_PROJECTILE means nothing without it
+//! ```
+//!
+//! Binary frames from the client:
+//! ```text
+//! input: u8 version, u8 kind=1, u32 seq, u8 buttons (bit order as in PlayerInput), f32 facing_rad
+//! ack:   u8 version, u8 kind=3, u64 tick
+//! ```
+
+use crate::messages::PlayerInput;
+use crate::server::AnimTargetId;
+use actix_web::HttpRequest;
+use gl_matrix::common::{Quat, Vec3};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const VERSION: u8 = 7;
+
+pub const SUBPROTOCOL_BINARY: &str = "game.bin.v7";
+pub const SUBPROTOCOL_JSON: &str = "game.json";
+pub const SUBPROTOCOLS: [&str; 2] = [SUBPROTOCOL_BINARY, SUBPROTOCOL_JSON];
+
+/// kind byte of binary messages
+pub const KIND_SNAPSHOT: u8 = 0;
+pub const KIND_INPUT: u8 = 1;
+pub const KIND_DELTA: u8 = 2;
+pub const KIND_ACK: u8 = 3;
+
+/// positions are quantized to i16 over -POS_RANGE..POS_RANGE (about 2mm resolution)
+pub const POS_RANGE: f32 = 64.0;
+
+const FLAG_TAKING_DMG: u8 = 1;
+const FLAG_HAS_PROJECTILE: u8 = 1 << 1;
+
+/// bits of the fields of a player in a delta
+const FIELD_POS: u16 = 1;
+const FIELD_QUAT: u16 = 1 << 1;
+const FIELD_HEALTH: u16 = 1 << 2;
+const FIELD_ANIM: u16 = 1 << 3;
+const FIELD_FLAGS: u16 = 1 << 4;
+const FIELD_PROJECTILE: u16 = 1 << 5;
+const FIELD_INPUT_SEQ: u16 = 1 << 6;
+const FIELD_PING: u16 = 1 << 7;
+const FIELD_NAME: u16 = 1 << 8;
+const FIELD_ALL: u16 = FIELD_POS
+    | FIELD_QUAT
+    | FIELD_HEALTH
+    | FIELD_ANIM
+    | FIELD_FLAGS
+    | FIELD_PROJECTILE
+    | FIELD_INPUT_SEQ
+    | FIELD_PING
+    | FIELD_NAME;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Binary,
+}
+
+impl Format {
+    /// pick the format from the first subprotocol requested by the client that we know
+    pub fn negotiate(req: &HttpRequest) -> Self {
+        let requested = req
+            .headers()
+            .get(actix_web::http::header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| {
+                h.split(',')
+                    .map(|p| p.trim())
+                    .find(|p| SUBPROTOCOLS.contains(p))
+            });
+        match requested {
+            Some(SUBPROTOCOL_BINARY) => Format::Binary,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// What clients need to render a player, without the derived matrices
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlayerState {
+    pub id: usize,
+    pub name: String,
+    /// the client replays its inputs after this one on top of this state to reconcile its prediction
+    pub last_input_seq: u32,
+    pub ping_ms: u16,
+    pub pos: Vec3,
+    pub quat: Quat,
+    pub health: f32,
+    pub is_taking_dmg: bool,
+    pub anim_target_id: AnimTargetId,
+    pub anim_ticks: u32,
+    pub projectile: Option<ProjectileState>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ProjectileState {
+    pub pos: Vec3,
+    pub quat: Quat,
+}
+
+/// The state of one room at one tick
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub players: Vec<PlayerState>,
+}
+
+/// The players that changed since a baseline snapshot the client already has
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Delta {
+    pub tick: u64,
+    pub baseline_tick: u64,
+    pub removed: Vec<usize>,
+    pub players: Vec<PlayerDelta>,
+}
+
+/// Only the fields that changed are Some
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlayerDelta {
+    pub id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pos: Option<Vec3>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quat: Option<Quat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anim: Option<(AnimTargetId, u32)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_taking_dmg: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub projectile: Option<Option<ProjectileState>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_input_seq: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_ms: Option<u16>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Frame<'a> {
+    Full(&'a Snapshot),
+    Delta(&'a Delta),
+}
+
+impl Snapshot {
+    /// encode as a delta against baseline if there is one, else as a full keyframe
+    pub fn encode(&self, format: Format, baseline: Option<&Snapshot>) -> Option<Message> {
+        let delta = baseline.map(|baseline| self.delta(baseline));
+        match format {
+            Format::Json => {
+                let frame = match &delta {
+                    Some(delta) => Frame::Delta(delta),
+                    None => Frame::Full(self),
+                };
+                serde_json::to_string(&frame).ok().map(Message::Text)
+            }
+            Format::Binary => match &delta {
+                Some(delta) => Some(Message::Binary(delta.to_bytes())),
+                None => Some(Message::Binary(self.to_bytes())),
+            },
+        }
+    }
+
+    pub fn delta(&self, baseline: &Snapshot) -> Delta {
+        let elapsed = self.tick.saturating_sub(baseline.tick) as u32;
+        let old: HashMap<usize, &PlayerState> =
+            baseline.players.iter().map(|p| (p.id, p)).collect();
+
+        let players = self
+            .players
+            .iter()
+            .filter_map(|p| {
+                let fields = match old.get(&p.id) {
+                    Some(b) => changed_fields(b, p, elapsed),
+                    None => FIELD_ALL,
+                };
+                (fields != 0).then(|| PlayerDelta::new(p, fields))
+            })
+            .collect();
+        let removed = baseline
+            .players
+            .iter()
+            .filter(|b| !self.players.iter().any(|p| p.id == b.id))
+            .map(|b| b.id)
+            .collect();
+
+        Delta {
+            tick: self.tick,
+            baseline_tick: baseline.tick,
+            removed,
+            players,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let n = self.players.len().min(u16::MAX as usize);
+        let mut buf = Vec::with_capacity(12 + n * 52);
+        buf.push(VERSION);
+        buf.push(KIND_SNAPSHOT);
+        buf.extend_from_slice(&self.tick.to_le_bytes());
+        put_u16(&mut buf, n as u16);
+        for p in self.players.iter().take(n) {
+            put_u16(&mut buf, p.id as u16);
+            buf.extend_from_slice(&p.last_input_seq.to_le_bytes());
+            put_u16(&mut buf, p.ping_ms);
+            put_pos(&mut buf, &p.pos);
+            put_quat(&mut buf, &p.quat);
+            put_health(&mut buf, p.health);
+            put_anim(&mut buf, p.anim_target_id, p.anim_ticks);
+            buf.push(flags(p));
+            if let Some(proj) = &p.projectile {
+                put_pos(&mut buf, &proj.pos);
+                put_quat(&mut buf, &proj.quat);
+            }
+            put_name(&mut buf, &p.name);
+        }
+        buf
+    }
+}
+
+impl PlayerDelta {
+    fn new(p: &PlayerState, fields: u16) -> Self {
+        let has = |field: u16| fields & field != 0;
+        Self {
+            id: p.id,
+            name: has(FIELD_NAME).then(|| p.name.clone()),
+            pos: has(FIELD_POS).then_some(p.pos),
+            quat: has(FIELD_QUAT).then_some(p.quat),
+            health: has(FIELD_HEALTH).then_some(p.health),
+            anim: has(FIELD_ANIM).then_some((p.anim_target_id, p.anim_ticks)),
+            is_taking_dmg: has(FIELD_FLAGS).then_some(p.is_taking_dmg),
+            projectile: has(FIELD_PROJECTILE).then_some(p.projectile),
+            last_input_seq: has(FIELD_INPUT_SEQ).then_some(p.last_input_seq),
+            ping_ms: has(FIELD_PING).then_some(p.ping_ms),
+        }
+    }
+
+    fn fields(&self) -> u16 {
+        let mut fields = 0;
+        let mut set = |present: bool, field: u16| {
+            if present {
+                fields |= field
+            }
+        };
+        set(self.pos.is_some(), FIELD_POS);
+        set(self.quat.is_some(), FIELD_QUAT);
+        set(self.health.is_some(), FIELD_HEALTH);
+        set(self.anim.is_some(), FIELD_ANIM);
+        set(self.is_taking_dmg.is_some(), FIELD_FLAGS);
+        set(self.projectile.is_some(), FIELD_PROJECTILE);
+        set(self.last_input_seq.is_some(), FIELD_INPUT_SEQ);
+        set(self.ping_ms.is_some(), FIELD_PING);
+        set(self.name.is_some(), FIELD_NAME);
+        fields
+    }
+}
+
+impl Delta {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let n_removed = self.removed.len().min(u16::MAX as usize);
+        let n_changed = self.players.len().min(u16::MAX as usize);
+        let mut buf = Vec::with_capacity(22 + n_removed * 2 + n_changed * 19);
+        buf.push(VERSION);
+        buf.push(KIND_DELTA);
+        buf.extend_from_slice(&self.tick.to_le_bytes());
+        buf.extend_from_slice(&self.baseline_tick.to_le_bytes());
+        put_u16(&mut buf, n_removed as u16);
+        for id in self.removed.iter().take(n_removed) {
+            put_u16(&mut buf, *id as u16);
+        }
+        put_u16(&mut buf, n_changed as u16);
+        for p in self.players.iter().take(n_changed) {
+            put_u16(&mut buf, p.id as u16);
+            put_u16(&mut buf, p.fields());
+            if let Some(pos) = &p.pos {
+                put_pos(&mut buf, pos);
+            }
+            if let Some(quat) = &p.quat {
+                put_quat(&mut buf, quat);
+            }
+            if let Some(health) = p.health {
+                put_health(&mut buf, health);
+            }
+            if let Some((anim_target_id, anim_ticks)) = p.anim {
+                put_anim(&mut buf, anim_target_id, anim_ticks);
+            }
+            if let Some(is_taking_dmg) = p.is_taking_dmg {
+                let mut flags = 0;
+                if is_taking_dmg {
+                    flags |= FLAG_TAKING_DMG;
+                }
+                if let Some(Some(_)) = p.projectile {
+                    flags |= FLAG_HAS_PROJECTILE;
+                }
+                buf.push(flags);
+            }
+            if let Some(Some(proj)) = &p.projectile {
+                put_pos(&mut buf, &proj.pos);
+                put_quat(&mut buf, &proj.quat);
+            }
+            if let Some(last_input_seq) = p.last_input_seq {
+                buf.extend_from_slice(&last_input_seq.to_le_bytes());
+            }
+            if let Some(ping_ms) = p.ping_ms {
+                put_u16(&mut buf, ping_ms);
+            }
+            if let Some(name) = &p.name {
+                put_name(&mut buf, name);
+            }
+        }
+        buf
+    }
+}
+
+/// which fields differ once quantized, so float noise below the wire resolution isnt resent
+fn changed_fields(old: &PlayerState, new: &PlayerState, elapsed_ticks: u32) -> u16 {
+    let mut fields = 0;
+    if quantize_pos(&old.pos) != quantize_pos(&new.pos) {
+        fields |= FIELD_POS;
+    }
+    if quantize_quat(&old.quat) != quantize_quat(&new.quat) {
+        fields |= FIELD_QUAT;
+    }
+    if quantize_health(old.health) != quantize_health(new.health) {
+        fields |= FIELD_HEALTH;
+    }
+    // the client advances anim_ticks itself, so only resend when that guess would be wrong
+    if old.anim_target_id != new.anim_target_id
+        || wire_anim_ticks(old.anim_ticks.saturating_add(elapsed_ticks))
+            != wire_anim_ticks(new.anim_ticks)
+    {
+        fields |= FIELD_ANIM;
+    }
+    if old.is_taking_dmg != new.is_taking_dmg {
+        fields |= FIELD_FLAGS;
+    }
+    if old.last_input_seq != new.last_input_seq {
+        fields |= FIELD_INPUT_SEQ;
+    }
+    if old.ping_ms != new.ping_ms {
+        fields |= FIELD_PING;
+    }
+    if old.name != new.name {
+        fields |= FIELD_NAME;
+    }
+    let quantize_proj =
+        |p: &Option<ProjectileState>| p.map(|p| (quantize_pos(&p.pos), quantize_quat(&p.quat)));
+    if quantize_proj(&old.projectile) != quantize_proj(&new.projectile) {
+        fields |= FIELD_FLAGS | FIELD_PROJECTILE;
+    }
+    fields
+}
+
+/// an encoded snapshot, ready to put in a websocket frame
+#[derive(Clone, Debug)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// What a client can send in a binary frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientFrame {
+    /// the id is filled in by the session
+    Input(PlayerInput),
+    /// tick of the last snapshot received
+    Ack(u64),
+}
+
+pub fn client_frame_from_bytes(bytes: &[u8]) -> Option<ClientFrame> {
+    match bytes {
+        [VERSION, KIND_INPUT, s0, s1, s2, s3, buttons, f0, f1, f2, f3] => {
+            let seq = u32::from_le_bytes([*s0, *s1, *s2, *s3]);
+            let buttons = *buttons;
+            let facing_rad = f32::from_le_bytes([*f0, *f1, *f2, *f3]);
+            Some(ClientFrame::Input(PlayerInput {
+                id: 0,
+                seq,
+                step_forward: buttons & 1 != 0,
+                step_backward: buttons & (1 << 1) != 0,
+                step_left: buttons & (1 << 2) != 0,
+                step_right: buttons & (1 << 3) != 0,
+                kick: buttons & (1 << 4) != 0,
+                punch: buttons & (1 << 5) != 0,
+                run: buttons & (1 << 6) != 0,
+                facing_rad,
+            }))
+        }
+        [VERSION, KIND_ACK, t @ ..] => {
+            Some(ClientFrame::Ack(u64::from_le_bytes(t.try_into().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+fn flags(p: &PlayerState) -> u8 {
+    let mut flags = 0;
+    if p.is_taking_dmg {
+        flags |= FLAG_TAKING_DMG;
+    }
+    if p.projectile.is_some() {
+        flags |= FLAG_HAS_PROJECTILE;
+    }
+    flags
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+/// names are short, anything past 255 bytes is cut at a char boundary
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    let mut len = name.len().min(u8::MAX as usize);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(&name.as_bytes()[..len]);
+}
+
+/// map -range..range to i16
+fn quantize(v: f32, range: f32) -> i16 {
+    ((v / range).clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+fn quantize_pos(pos: &Vec3) -> [i16; 3] {
+    pos.map(|v| quantize(v, POS_RANGE))
+}
+
+fn quantize_quat(quat: &Quat) -> [i16; 4] {
+    quat.map(|v| quantize(v, 1.0))
+}
+
+fn quantize_health(health: f32) -> u8 {
+    health.round().clamp(0.0, 255.0) as u8
+}
+
+fn put_pos(buf: &mut Vec<u8>, pos: &Vec3) {
+    for v in quantize_pos(pos) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn put_quat(buf: &mut Vec<u8>, quat: &Quat) {
+    for v in quantize_quat(quat) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn put_health(buf: &mut Vec<u8>, health: f32) {
+    buf.push(quantize_health(health));
+}
+
+/// anim_ticks as the client sees it, it stops at u16::MAX
+fn wire_anim_ticks(anim_ticks: u32) -> u16 {
+    anim_ticks.min(u16::MAX as u32) as u16
+}
+
+fn put_anim(buf: &mut Vec<u8>, anim_target_id: AnimTargetId, anim_ticks: u32) {
+    buf.push(anim_target_id as u8);
+    put_u16(buf, wire_anim_ticks(anim_ticks));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// half a step of the i16 grid, and a little for f32 rounding
+    const POS_EPS: f32 = POS_RANGE / i16::MAX as f32 * 0.501;
+    const QUAT_EPS: f32 = 1.0 / i16::MAX as f32 * 0.501;
+
+    fn player(id: usize) -> PlayerState {
+        PlayerState {
+            id,
+            name: format!("player{id}"),
+            last_input_seq: 1000 + id as u32,
+            ping_ms: 40,
+            pos: [1.25, 0.0, -3.5],
+            quat: [0.0, 0.6, 0.0, 0.8],
+            health: 87.0,
+            is_taking_dmg: false,
+            anim_target_id: AnimTargetId::Walk,
+            anim_ticks: 12,
+            projectile: None,
+        }
+    }
+
+    /// reads frames back the way a client does, following the layout in the module doc
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take<const N: usize>(&mut self) -> [u8; N] {
+            let (head, rest) = self.0.split_at(N);
+            self.0 = rest;
+            head.try_into().unwrap()
+        }
+        fn u8(&mut self) -> u8 {
+            self.take::<1>()[0]
+        }
+        fn u16(&mut self) -> u16 {
+            u16::from_le_bytes(self.take())
+        }
+        fn u32(&mut self) -> u32 {
+            u32::from_le_bytes(self.take())
+        }
+        fn u64(&mut self) -> u64 {
+            u64::from_le_bytes(self.take())
+        }
+        fn unquantize(&mut self, range: f32) -> f32 {
+            i16::from_le_bytes(self.take()) as f32 / i16::MAX as f32 * range
+        }
+        fn pos(&mut self) -> Vec3 {
+            [(); 3].map(|_| self.unquantize(POS_RANGE))
+        }
+        fn quat(&mut self) -> Quat {
+            [(); 4].map(|_| self.unquantize(1.0))
+        }
+        fn anim(&mut self) -> (AnimTargetId, u32) {
+            let anim = match self.u8() {
+                0 => AnimTargetId::Idle,
+                1 => AnimTargetId::Walk,
+                2 => AnimTargetId::Kick,
+                3 => AnimTargetId::Punch,
+                n => panic!("no animation {n}"),
+            };
+            (anim, self.u16() as u32)
+        }
+        fn projectile(&mut self) -> ProjectileState {
+            ProjectileState {
+                pos: self.pos(),
+                quat: self.quat(),
+            }
+        }
+        fn name(&mut self) -> String {
+            let len = self.u8() as usize;
+            let (name, rest) = self.0.split_at(len);
+            self.0 = rest;
+            String::from_utf8(name.to_vec()).unwrap()
+        }
+    }
+
+    fn decode_snapshot(bytes: &[u8]) -> Snapshot {
+        let mut r = Reader(bytes);
+        assert_eq!((r.u8(), r.u8()), (VERSION, KIND_SNAPSHOT));
+        let tick = r.u64();
+        let players = (0..r.u16())
+            .map(|_| {
+                let id = r.u16() as usize;
+                let last_input_seq = r.u32();
+                let ping_ms = r.u16();
+                let pos = r.pos();
+                let quat = r.quat();
+                let health = r.u8() as f32;
+                let (anim_target_id, anim_ticks) = r.anim();
+                let flags = r.u8();
+                let projectile = (flags & FLAG_HAS_PROJECTILE != 0).then(|| r.projectile());
+                PlayerState {
+                    id,
+                    last_input_seq,
+                    ping_ms,
+                    pos,
+                    quat,
+                    health,
+                    is_taking_dmg: flags & FLAG_TAKING_DMG != 0,
+                    anim_target_id,
+                    anim_ticks,
+                    projectile,
+                    name: r.name(),
+                }
+            })
+            .collect();
+        assert!(r.0.is_empty(), "{} bytes left over", r.0.len());
+        Snapshot { tick, players }
+    }
+
+    fn decode_delta(bytes: &[u8]) -> Delta {
+        let mut r = Reader(bytes);
+        assert_eq!((r.u8(), r.u8()), (VERSION, KIND_DELTA));
+        let tick = r.u64();
+        let baseline_tick = r.u64();
+        let removed = (0..r.u16()).map(|_| r.u16() as usize).collect();
+        let players = (0..r.u16())
+            .map(|_| {
+                let id = r.u16() as usize;
+                let fields = r.u16();
+                let has = |field: u16| fields & field != 0;
+                let pos = has(FIELD_POS).then(|| r.pos());
+                let quat = has(FIELD_QUAT).then(|| r.quat());
+                let health = has(FIELD_HEALTH).then(|| r.u8() as f32);
+                let anim = has(FIELD_ANIM).then(|| r.anim());
+                let flags = has(FIELD_FLAGS).then(|| r.u8());
+                let projectile = has(FIELD_PROJECTILE).then(|| {
+                    let flags = flags.expect("projectile without flags");
+                    (flags & FLAG_HAS_PROJECTILE != 0).then(|| r.projectile())
+                });
+                PlayerDelta {
+                    id,
+                    pos,
+                    quat,
+                    health,
+                    anim,
+                    is_taking_dmg: flags.map(|f| f & FLAG_TAKING_DMG != 0),
+                    projectile,
+                    last_input_seq: has(FIELD_INPUT_SEQ).then(|| r.u32()),
+                    ping_ms: has(FIELD_PING).then(|| r.u16()),
+                    name: has(FIELD_NAME).then(|| r.name()),
+                }
+            })
+            .collect();
+        assert!(r.0.is_empty(), "{} bytes left over", r.0.len());
+        Delta {
+            tick,
+            baseline_tick,
+            removed,
+            players,
+        }
+    }
+
+    /// what the client makes of a delta on top of the baseline it acked
+    fn apply(baseline: &Snapshot, delta: &Delta) -> Snapshot {
+        assert_eq!(baseline.tick, delta.baseline_tick);
+        let elapsed = (delta.tick - baseline.tick) as u32;
+        let mut players: Vec<PlayerState> = baseline
+            .players
+            .iter()
+            .filter(|p| !delta.removed.contains(&p.id))
+            .cloned()
+            .map(|mut p| {
+                p.anim_ticks = (p.anim_ticks + elapsed).min(u16::MAX as u32);
+                p
+            })
+            .collect();
+        for d in &delta.players {
+            let p = match players.iter_mut().find(|p| p.id == d.id) {
+                Some(p) => p,
+                None => {
+                    players.push(PlayerState {
+                        id: d.id,
+                        ..player(0)
+                    });
+                    players.last_mut().unwrap()
+                }
+            };
+            p.pos = d.pos.unwrap_or(p.pos);
+            p.quat = d.quat.unwrap_or(p.quat);
+            p.health = d.health.unwrap_or(p.health);
+            (p.anim_target_id, p.anim_ticks) = d.anim.unwrap_or((p.anim_target_id, p.anim_ticks));
+            p.is_taking_dmg = d.is_taking_dmg.unwrap_or(p.is_taking_dmg);
+            p.projectile = d.projectile.unwrap_or(p.projectile);
+            p.last_input_seq = d.last_input_seq.unwrap_or(p.last_input_seq);
+            p.ping_ms = d.ping_ms.unwrap_or(p.ping_ms);
+            p.name = d.name.clone().unwrap_or_else(|| p.name.clone());
+        }
+        Snapshot {
+            tick: delta.tick,
+            players,
+        }
+    }
+
+    fn assert_close(a: &[f32], b: &[f32], eps: f32) {
+        for (a, b) in a.iter().zip(b) {
+            assert!(
+                (a - b).abs() <= eps,
+                "{a} and {b} are more than {eps} apart"
+            );
+        }
+    }
+
+    fn assert_same_player(decoded: &PlayerState, sent: &PlayerState) {
+        assert_close(&decoded.pos, &sent.pos, POS_EPS);
+        assert_close(&decoded.quat, &sent.quat, QUAT_EPS);
+        assert_eq!(decoded.projectile.is_some(), sent.projectile.is_some());
+        if let (Some(a), Some(b)) = (&decoded.projectile, &sent.projectile) {
+            assert_close(&a.pos, &b.pos, POS_EPS);
+            assert_close(&a.quat, &b.quat, QUAT_EPS);
+        }
+        // the floats were checked above
+        let exact = |p: &PlayerState| PlayerState {
+            pos: [0.0; 3],
+            quat: [0.0; 4],
+            projectile: None,
+            ..p.clone()
+        };
+        assert_eq!(exact(decoded), exact(sent));
+    }
+
+    #[test]
+    fn snapshot_round_trips_through_bytes() {
+        let mut shooting = player(7);
+        shooting.is_taking_dmg = true;
+        shooting.anim_target_id = AnimTargetId::Kick;
+        shooting.projectile = Some(ProjectileState {
+            pos: [-10.0, 1.5, 63.9],
+            quat: [0.5, -0.5, 0.5, -0.5],
+        });
+        shooting.name = String::from("ünïcødé");
+        let snapshot = Snapshot {
+            tick: u64::MAX - 3,
+            players: vec![player(1), shooting, player(u16::MAX as usize)],
+        };
+
+        let decoded = decode_snapshot(&snapshot.to_bytes());
+        assert_eq!(decoded.tick, snapshot.tick);
+        assert_eq!(decoded.players.len(), snapshot.players.len());
+        for (decoded, sent) in decoded.players.iter().zip(&snapshot.players) {
+            assert_same_player(decoded, sent);
+        }
+    }
+
+    #[test]
+    fn empty_snapshot_is_just_the_header() {
+        let snapshot = Snapshot {
+            tick: 5,
+            players: vec![],
+        };
+        let bytes = snapshot.to_bytes();
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(decode_snapshot(&bytes), snapshot);
+    }
+
+    #[test]
+    fn long_names_are_cut_at_a_char_boundary() {
+        let mut p = player(1);
+        p.name = "å".repeat(200);
+        let bytes = Snapshot {
+            tick: 1,
+            players: vec![p],
+        }
+        .to_bytes();
+        let name = &decode_snapshot(&bytes).players[0].name;
+        assert_eq!(name, &"å".repeat(127));
+    }
+
+    #[test]
+    fn json_snapshot_is_a_tagged_full_frame() {
+        let snapshot = Snapshot {
+            tick: 3,
+            players: vec![player(1)],
+        };
+        let json = match snapshot.encode(Format::Json, None) {
+            Some(Message::Text(s)) => s,
+            other => panic!("expected text, got {other:?}"),
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["kind"], "full");
+        assert_eq!(value["tick"], 3);
+        assert_eq!(value["players"][0]["name"], "player1");
+    }
+
+    fn snapshot(tick: u64, players: Vec<PlayerState>) -> Snapshot {
+        Snapshot { tick, players }
+    }
+
+    /// bytes of a delta with nothing removed and nothing changed
+    const EMPTY_DELTA_LEN: usize = 22;
+
+    #[test]
+    fn unchanged_player_costs_no_bytes() {
+        let baseline = snapshot(10, vec![player(1), player(2)]);
+        let mut moved = player(2);
+        moved.pos[0] += 1.0;
+        // the client advances the animation itself
+        let mut later = snapshot(13, vec![player(1), moved]);
+        for p in &mut later.players {
+            p.anim_ticks += 3;
+        }
+
+        let delta = later.delta(&baseline);
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.players[0].id, 2);
+
+        later.players.truncate(1);
+        let delta = later.delta(&snapshot(10, vec![player(1)]));
+        assert!(delta.players.is_empty() && delta.removed.is_empty());
+        assert_eq!(delta.to_bytes().len(), EMPTY_DELTA_LEN);
+    }
+
+    #[test]
+    fn anim_ticks_stop_at_the_same_place_in_keyframes_and_deltas() {
+        let mut old = player(1);
+        old.anim_ticks = u16::MAX as u32 - 2;
+        let baseline = snapshot(10, vec![old.clone()]);
+        for (tick, anim_ticks) in [(12, old.anim_ticks + 2), (15, old.anim_ticks + 5)] {
+            let mut p = old.clone();
+            p.anim_ticks = anim_ticks;
+            let later = snapshot(tick, vec![p]);
+            let delta = decode_delta(&later.delta(&baseline).to_bytes());
+            let keyframe = decode_snapshot(&later.to_bytes());
+            let client = apply(&decode_snapshot(&baseline.to_bytes()), &delta);
+            assert_eq!(client.players[0].anim_ticks, keyframe.players[0].anim_ticks);
+        }
+
+        // long stopped, a tick the room skipped doesnt make it resend anything
+        let mut old = player(1);
+        old.anim_ticks = 70_000;
+        let mut p = old.clone();
+        p.anim_ticks += 1;
+        let delta = snapshot(13, vec![p]).delta(&snapshot(10, vec![old]));
+        assert!(delta.players.is_empty());
+    }
+
+    #[test]
+    fn changes_below_the_wire_resolution_are_not_sent() {
+        let baseline = snapshot(10, vec![player(1)]);
+        let mut p = player(1);
+        p.anim_ticks += 1;
+        p.pos[0] += POS_EPS / 10.0;
+        p.health += 0.2;
+        assert!(snapshot(11, vec![p]).delta(&baseline).players.is_empty());
+    }
+
+    #[test]
+    fn single_changed_field_is_sent_alone() {
+        let baseline = snapshot(10, vec![player(1)]);
+        let mut p = player(1);
+        p.anim_ticks += 1;
+        p.health -= 10.0;
+        let delta = snapshot(11, vec![p.clone()]).delta(&baseline);
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.players[0].fields(), FIELD_HEALTH);
+
+        let bytes = delta.to_bytes();
+        // id, fields and the health byte
+        assert_eq!(bytes.len(), EMPTY_DELTA_LEN + 2 + 2 + 1);
+        let decoded = decode_delta(&bytes);
+        assert_eq!(decoded, delta);
+        assert_same_player(&apply(&baseline, &decoded).players[0], &p);
+    }
+
+    #[test]
+    fn projectile_is_sent_with_flags() {
+        let baseline = snapshot(10, vec![player(1)]);
+        let mut p = player(1);
+        p.anim_ticks += 1;
+        p.projectile = Some(ProjectileState {
+            pos: [1.0, 1.0, 1.0],
+            quat: [0.0, 0.0, 0.0, 1.0],
+        });
+        let delta = snapshot(11, vec![p.clone()]).delta(&baseline);
+        assert_eq!(delta.players[0].fields(), FIELD_FLAGS | FIELD_PROJECTILE);
+        let decoded = apply(&baseline, &decode_delta(&delta.to_bytes()));
+        assert_same_player(&decoded.players[0], &p);
+
+        // and going away again
+        let gone = snapshot(12, vec![player(1)]);
+        let delta = gone.delta(&snapshot(11, vec![p]));
+        assert_eq!(delta.players[0].projectile, Some(None));
+        let decoded = decode_delta(&delta.to_bytes());
+        assert_eq!(decoded.players[0].projectile, Some(None));
+    }
+
+    #[test]
+    fn added_player_is_sent_in_full_and_removed_one_by_id() {
+        let baseline = snapshot(10, vec![player(1), player(2)]);
+        let mut new = player(3);
+        new.name = String::from("newcomer");
+        let later = snapshot(11, vec![player(1), new]);
+        let mut expected = later.clone();
+        expected.players[0].anim_ticks += 1;
+
+        let delta = expected.delta(&baseline);
+        assert_eq!(delta.removed, vec![2]);
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.players[0].id, 3);
+        assert_eq!(delta.players[0].fields(), FIELD_ALL);
+
+        let decoded = apply(&baseline, &decode_delta(&delta.to_bytes()));
+        assert_eq!(decoded.players.len(), 2);
+        for (decoded, sent) in decoded.players.iter().zip(&expected.players) {
+            assert_same_player(decoded, sent);
+        }
+    }
+
+    #[test]
+    fn delta_applied_to_its_baseline_gives_the_snapshot() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut baseline = snapshot(100, (1..=8).map(player).collect());
+        for tick in 101..200 {
+            let mut next = baseline.clone();
+            next.tick = tick;
+            for p in &mut next.players {
+                p.anim_ticks += 1;
+                if rng.gen_bool(0.3) {
+                    p.pos[rng.gen_range(0..3)] = rng.gen_range(-20.0..20.0);
+                }
+                if rng.gen_bool(0.1) {
+                    p.health = rng.gen_range(0.0..100.0_f32).round();
+                }
+                if rng.gen_bool(0.1) {
+                    p.anim_target_id = AnimTargetId::Punch;
+                    p.anim_ticks = 0;
+                }
+                if rng.gen_bool(0.05) {
+                    p.last_input_seq += 1;
+                }
+            }
+            let decoded = apply(&baseline, &decode_delta(&next.delta(&baseline).to_bytes()));
+            assert_eq!(decoded.tick, tick);
+            for (decoded, sent) in decoded.players.iter().zip(&next.players) {
+                assert_same_player(decoded, sent);
+            }
+            // the client keeps what it decoded, so the next baseline carries the rounding
+            baseline = decoded;
+        }
+    }
+
+    #[test]
+    fn encodes_a_keyframe_without_a_baseline() {
+        let baseline = snapshot(10, vec![player(1)]);
+        let later = snapshot(11, vec![player(1)]);
+        let kind = |baseline: Option<&Snapshot>| match later.encode(Format::Binary, baseline) {
+            Some(Message::Binary(bytes)) => bytes[1],
+            other => panic!("expected binary, got {other:?}"),
+        };
+        assert_eq!(kind(Some(&baseline)), KIND_DELTA);
+        assert_eq!(kind(None), KIND_SNAPSHOT);
+
+        let json = match later.encode(Format::Json, Some(&baseline)) {
+            Some(Message::Text(s)) => s,
+            other => panic!("expected text, got {other:?}"),
+        };
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["kind"], "delta");
+        assert_eq!(value["baseline_tick"], 10);
+    }
+
+    #[test]
+    fn quantization_stays_within_half_a_step() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..10_000 {
+            let v = rng.gen_range(-POS_RANGE..=POS_RANGE);
+            let back = quantize(v, POS_RANGE) as f32 / i16::MAX as f32 * POS_RANGE;
+            assert!((back - v).abs() <= POS_EPS, "{v} came back as {back}");
+
+            let q = rng.gen_range(-1.0..=1.0);
+            let back = quantize(q, 1.0) as f32 / i16::MAX as f32;
+            assert!((back - q).abs() <= QUAT_EPS, "{q} came back as {back}");
+        }
+    }
+
+    #[test]
+    fn quantization_clamps_out_of_range_values() {
+        assert_eq!(quantize(POS_RANGE, POS_RANGE), i16::MAX);
+        assert_eq!(quantize(-POS_RANGE, POS_RANGE), -i16::MAX);
+        assert_eq!(quantize(POS_RANGE * 10.0, POS_RANGE), i16::MAX);
+        assert_eq!(quantize(f32::NEG_INFINITY, POS_RANGE), -i16::MAX);
+        assert_eq!(quantize(1.5, 1.0), i16::MAX);
+        assert_eq!(quantize(0.0, POS_RANGE), 0);
+
+        assert_eq!(quantize_health(-20.0), 0);
+        assert_eq!(quantize_health(99.6), 100);
+        assert_eq!(quantize_health(1000.0), 255);
+    }
+
+    fn input_bytes(seq: u32, buttons: u8, facing_rad: f32) -> Vec<u8> {
+        let mut bytes = vec![VERSION, KIND_INPUT];
+        bytes.extend_from_slice(&seq.to_le_bytes());
+        bytes.push(buttons);
+        bytes.extend_from_slice(&facing_rad.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn client_input_frame_decodes() {
+        let frame = client_frame_from_bytes(&input_bytes(42, 0b101_0011, -1.5));
+        let expected = PlayerInput {
+            seq: 42,
+            step_forward: true,
+            step_backward: true,
+            kick: true,
+            run: true,
+            facing_rad: -1.5,
+            ..PlayerInput::new()
+        };
+        assert_eq!(frame, Some(ClientFrame::Input(expected)));
+    }
+
+    #[test]
+    fn client_ack_frame_decodes() {
+        let mut bytes = vec![VERSION, KIND_ACK];
+        bytes.extend_from_slice(&123_456_789_u64.to_le_bytes());
+        assert_eq!(
+            client_frame_from_bytes(&bytes),
+            Some(ClientFrame::Ack(123_456_789))
+        );
+    }
+
+    #[test]
+    fn truncated_or_padded_client_frames_are_rejected() {
+        let mut ack = vec![VERSION, KIND_ACK];
+        ack.extend_from_slice(&9_u64.to_le_bytes());
+        for frame in [input_bytes(1, 0, 0.0), ack] {
+            for len in 0..frame.len() {
+                assert_eq!(client_frame_from_bytes(&frame[..len]), None, "{len} bytes");
+            }
+            let mut padded = frame.clone();
+            padded.push(0);
+            assert_eq!(client_frame_from_bytes(&padded), None);
+        }
+    }
+
+    #[test]
+    fn client_frames_of_another_version_or_kind_are_rejected() {
+        let mut bytes = input_bytes(1, 0, 0.0);
+        bytes[0] = VERSION - 1;
+        assert_eq!(client_frame_from_bytes(&bytes), None);
+        for kind in [KIND_SNAPSHOT, KIND_DELTA, 200] {
+            let mut bytes = input_bytes(1, 0, 0.0);
+            bytes[1] = kind;
+            assert_eq!(client_frame_from_bytes(&bytes), None);
+        }
+    }
+
+    #[test]
+    fn garbage_client_frames_dont_panic() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..32);
+            let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if len > 1 && rng.gen_bool(0.5) {
+                // get past the version and kind checks now and then
+                bytes[0] = VERSION;
+                bytes[1] = rng.gen_range(0..4);
+            }
+            let _ = client_frame_from_bytes(&bytes);
+        }
+    }
+}
@@ -1,183 +1,477 @@
+use crate::config::{Config, Settings};
 use crate::messages;
+use crate::metrics::{self, Disconnect, Metrics};
+use crate::session::Session;
 use actix::prelude::*;
-use rand::{self, rngs::ThreadRng, Rng};
-use std::time::Duration;
+use actix_web_actors::ws::CloseCode;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::HashMap,
+    net::IpAddr,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use self::components::Player;
+pub use self::audit::AuditLog;
+use self::chat::ChatHistory;
+pub use self::components::{AnimTargetId, Player, PlayerInput};
+use self::ids::IdAllocator;
+pub use self::ids::SessionToken;
+pub use self::input::OverflowPolicy;
+pub use self::moderation::BanList;
+use self::moderation::{Ban, BanTarget, WordFilter};
+pub use self::names::NameError;
+pub use self::replay::{Playback, Replay};
+pub use self::room::Room;
+pub use self::room::RoomConfig;
+mod audit;
+mod chat;
+mod clock;
 mod components;
+mod history;
+mod ids;
+mod input;
+mod moderation;
+mod names;
+mod replay;
+mod room;
 mod systems;
+mod validation;
+mod world;
 
-const TICK_MS: u64 = 17;
-pub const TICKS_PER_SECOND: f32 = 1000.0 / TICK_MS as f32;
-
-const TICK_INTERVAL: Duration = Duration::from_millis(TICK_MS);
-//const TICK_INTERVAL: Duration = Duration::from_millis(17);
-//const TICK_INTERVAL: Duration = Duration::from_millis(1000);
-//const TICK_INTERVAL: Duration = Duration::from_millis(34);
+pub const DEFAULT_ROOM: &str = "main";
 
+/// Lobby/registry of rooms. Knows every session and which room it is in,
+/// but the simulation itself runs in the room actors.
 #[derive(Debug)]
 pub struct Server {
-    players: HashMap<usize, Player>,
-    sessions: HashMap<usize, Recipient<messages::GameStateMessage>>,
-    rooms: HashMap<String, HashSet<usize>>,
-    rng: ThreadRng,
+    ids: IdAllocator,
+    /// player id of each session, including dropped ones that can still resume
+    tokens: HashMap<SessionToken, usize>,
+    sessions: HashMap<usize, Addr<Session>>,
+    /// players whose connection dropped, removed when the timer runs out
+    detached: HashMap<usize, SpawnHandle>,
+    resume_grace: Duration,
+    rooms: HashMap<String, Addr<Room>>,
+    /// which room each session is currently in
+    session_rooms: HashMap<usize, String>,
+    /// display names players picked, the others go by player<id>
+    names: HashMap<usize, String>,
+    chat: HashMap<String, ChatHistory>,
+    chat_history_len: usize,
+    filter: WordFilter,
+    /// players a moderator muted, and until when
+    muted: HashMap<usize, Instant>,
+    /// address each player connected from, for ip bans
+    ips: HashMap<usize, IpAddr>,
+    bans: BanList,
+    /// rooms are spread over these so they tick in parallel
+    arbiters: Vec<Arbiter>,
+    next_arbiter: usize,
+    /// what the server was started with, with the settings changed at runtime
+    config: Config,
+    room_config: RoomConfig,
+    audit: AuditLog,
+    metrics: Arc<Metrics>,
     visitor_count: Arc<AtomicUsize>,
 }
 
 impl Server {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> Server {
-        // default room
-        let mut rooms = HashMap::new();
-        rooms.insert("main".to_owned(), HashSet::new());
-
-        Server {
-            players: HashMap::with_capacity(10),
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        config: &Config,
+        audit: AuditLog,
+        bans: BanList,
+        metrics: Arc<Metrics>,
+    ) -> Server {
+        let n_arbiters = match config.room_threads {
+            0 => std::thread::available_parallelism().map_or(2, |n| n.get()),
+            n => n,
+        };
+
+        let mut server = Server {
+            ids: IdAllocator::new(),
+            tokens: HashMap::with_capacity(10),
             sessions: HashMap::with_capacity(10),
-            rooms,
-            rng: rand::thread_rng(),
+            detached: HashMap::new(),
+            resume_grace: config.resume_grace(),
+            rooms: HashMap::new(),
+            session_rooms: HashMap::with_capacity(10),
+            names: HashMap::with_capacity(10),
+            chat: HashMap::new(),
+            chat_history_len: config.chat_history_len,
+            filter: WordFilter::new(&config.word_filter),
+            muted: HashMap::new(),
+            ips: HashMap::with_capacity(10),
+            bans,
+            arbiters: (0..n_arbiters).map(|_| Arbiter::new()).collect(),
+            next_arbiter: 0,
+            config: config.clone(),
+            room_config: RoomConfig::new(config),
+            audit,
+            metrics,
             visitor_count,
+        };
+        // default room
+        server.room_addr(DEFAULT_ROOM);
+        server
+    }
+
+    fn name_of(&self, id: usize) -> String {
+        match self.names.get(&id) {
+            Some(name) => name.clone(),
+            None => names::default_name(id),
         }
     }
 
-    /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(messages::GameStateMessage(message.to_owned()));
-                    }
-                }
+    /// the player in the room going by that name
+    fn find_by_name(&self, room: &str, name: &str) -> Option<usize> {
+        self.session_rooms
+            .iter()
+            .find(|(id, r)| *r == room && names::same(&self.name_of(**id), name))
+            .map(|(id, _)| *id)
+    }
+
+    fn id_of_token(&self, token: &SessionToken) -> Option<usize> {
+        self.tokens.get(token).copied()
+    }
+
+    fn token_of(&self, id: usize) -> Option<SessionToken> {
+        self.tokens
+            .iter()
+            .find(|(_, i)| **i == id)
+            .map(|(token, _)| *token)
+    }
+
+    /// how much longer a moderator muted the player for
+    fn muted_for(&mut self, id: usize) -> Option<Duration> {
+        let left = self.muted.get(&id)?.checked_duration_since(Instant::now());
+        if left.is_none() {
+            self.muted.remove(&id);
+        }
+        left
+    }
+
+    /// tell a muted player its chat went nowhere, false if it isnt muted
+    fn refuse_muted(&mut self, id: usize, kind: &str) -> bool {
+        let left = match self.muted_for(id) {
+            Some(left) => left,
+            None => return false,
+        };
+        if let Some(addr) = self.sessions.get(&id) {
+            addr.do_send(messages::ServerMessage::Error {
+                code: messages::ErrorCode::Muted,
+                rejected_kind: Some(kind.to_owned()),
+                detail: format!("muted by a moderator for {}s", left.as_secs() + 1),
+            });
+        }
+        true
+    }
+
+    /// send message to every client in the room, and keep it for the ones joining later
+    fn say_in_room(&mut self, room: &str, msg: messages::ServerMessage) {
+        for (id, r) in &self.session_rooms {
+            if let (true, Some(addr)) = (r == room, self.sessions.get(id)) {
+                addr.do_send(msg.clone());
             }
         }
+        let len = self.chat_history_len;
+        self.chat
+            .entry(room.to_owned())
+            .or_insert_with(|| ChatHistory::new(len))
+            .push(msg);
+    }
+
+    fn system_message(&mut self, room: &str, text: String) {
+        self.say_in_room(room, messages::ServerMessage::System { text });
     }
 
-    /// send message to all clients
-    fn broadcast(&self, msg: &str) {
-        for recipient in self.sessions.values() {
-            recipient.do_send(messages::GameStateMessage(msg.to_owned()));
+    /// replay what was said in the room to a player that just came in
+    fn send_chat_history(&self, id: usize, room: &str) {
+        if let (Some(history), Some(addr)) = (self.chat.get(room), self.sessions.get(&id)) {
+            for msg in history.iter() {
+                addr.do_send(msg.clone());
+            }
         }
     }
 
-    fn tick(&mut self) {
-        if self.players.len() < 1 {
-            return;
+    /// address of the room, starting it on the next arbiter if it doesnt exist
+    fn room_addr(&mut self, name: &str) -> Addr<Room> {
+        if let Some(addr) = self.rooms.get(name) {
+            return addr.clone();
+        }
+        let arbiter = &self.arbiters[self.next_arbiter];
+        self.next_arbiter = (self.next_arbiter + 1) % self.arbiters.len();
+
+        let room = Room::new(
+            name,
+            self.room_config,
+            self.audit.clone(),
+            self.metrics.clone(),
+            self.config.replay_dir.as_deref(),
+        );
+        let addr = Room::start_in_arbiter(&arbiter.handle(), |_| room);
+        self.rooms.insert(name.to_owned(), addr.clone());
+        addr
+    }
+
+    /// hand the player of an earlier session to a new one, with a new token so the old one is spent
+    fn resume(
+        &mut self,
+        token: SessionToken,
+        addr: &Addr<Session>,
+        ip: Option<IpAddr>,
+        ctx: &mut Context<Self>,
+    ) -> Option<messages::Connected> {
+        let id = *self.tokens.get(&token)?;
+        let name = self.session_rooms.get(&id)?.clone();
+        self.tokens.remove(&token);
+
+        if let Some(handle) = self.detached.remove(&id) {
+            ctx.cancel_future(handle);
+        } else if let Some(old) = self.sessions.get(&id) {
+            // the old connection is dead but hasnt timed out yet
+            old.do_send(messages::CloseSessionMessage {
+                why: Disconnect::Replaced,
+                code: CloseCode::Normal,
+                reason: String::from("resumed from another connection"),
+            });
         }
-        //println!("server tick, sending to all sessions");
-        for (_id, player) in &mut self.players {
-            player.apply();
+        if self.sessions.insert(id, addr.clone()).is_none() {
+            let _oldcount = self.visitor_count.fetch_add(1, Ordering::SeqCst);
+        }
+        let token = SessionToken::generate();
+        self.tokens.insert(token, id);
+        if let Some(ip) = ip {
+            self.ips.insert(id, ip);
         }
 
-        systems::run(&mut self.players);
+        let room = self.room_addr(&name);
+        room.do_send(messages::AttachSessionMessage {
+            id,
+            addr: addr.clone().recipient(),
+        });
+        self.send_chat_history(id, &name);
+        tracing::info!(player = id, room = %name, "player resumed");
+        Some(messages::Connected {
+            id,
+            token,
+            room,
+            resumed: true,
+        })
+    }
 
-        let serialized = serde_json::to_string(&self.players);
-        match serialized {
-            Ok(serialized_renderable) => self.broadcast(&serialized_renderable),
-            Err(_) => println!("failed to serialize renderable, not broadcasting anything"),
+    /// take the player out of the world for good, why goes after its name in the room
+    fn remove_player(&mut self, token: SessionToken, why: &str) {
+        let id = match self.tokens.remove(&token) {
+            Some(id) => id,
+            None => return,
+        };
+        tracing::info!(player = id, why, "player removed");
+
+        self.ids.free(id);
+        self.detached.remove(&id);
+        let player_name = self.name_of(id);
+        self.names.remove(&id);
+        self.muted.remove(&id);
+        self.ips.remove(&id);
+        if let Some(name) = self.session_rooms.remove(&id) {
+            if let Some(addr) = self.rooms.get(&name) {
+                addr.do_send(messages::RemovePlayerMessage { id });
+            }
+            self.system_message(&name, format!("{player_name} {why}"));
+            self.close_room_if_empty(&name);
         }
     }
 
-    fn start_tick_interval(&self, ctx: &mut Context<Self>) {
-        ctx.run_interval(TICK_INTERVAL, |act, _ctx| act.tick());
+    /// close the players connection and remove it without waiting for a resume
+    fn kick(&mut self, id: usize, reason: &str) -> bool {
+        let token = match self.token_of(id) {
+            Some(token) => token,
+            None => return false,
+        };
+        if let Some(addr) = self.sessions.remove(&id) {
+            let _oldcount = self.visitor_count.fetch_sub(1, Ordering::SeqCst);
+            addr.do_send(messages::CloseSessionMessage {
+                why: Disconnect::Kicked,
+                code: CloseCode::Policy,
+                reason: reason.to_owned(),
+            });
+        }
+        self.remove_player(token, &format!("was {reason}"));
+        true
     }
 
-    fn apply_player_input(&mut self, player_id: usize, player_input: messages::PlayerInput) {
-        //let player = self.players.entry(player_id).or_insert(Player::new());
-        //player.player_input = player_input;
-        self.players
-            .entry(player_id)
-            .and_modify(|p| p.player_input = player_input);
+    /// kick the player and keep its address out. the resume token is spent by the kick and a
+    /// client reconnecting without one gets a new player, so the address is all there is to ban
+    fn ban(&mut self, id: usize, reason: String, duration: Option<Duration>) -> Result<(), String> {
+        let ip = match self.ips.get(&id) {
+            Some(ip) => *ip,
+            None => return Err(String::from("its address is unknown, kick it instead")),
+        };
+        self.bans.add(Ban {
+            target: BanTarget::Ip(ip),
+            reason: reason.clone(),
+            expires: duration.map(|d| moderation::unix_now() + d.as_secs()),
+        });
+        if let Err(e) = self.bans.save() {
+            tracing::error!(error = %e, "failed to save bans");
+        }
+        let why = match reason.is_empty() {
+            true => String::from("banned"),
+            false => format!("banned: {reason}"),
+        };
+        self.kick(id, &why);
+        Ok(())
+    }
+
+    /// move a connected player to the room, resolves to the room once the entity is handed over
+    fn move_player(
+        &mut self,
+        id: usize,
+        name: String,
+    ) -> ResponseActFuture<Self, Result<Addr<Room>, String>> {
+        let (old_name, session_addr) = match (self.session_rooms.get(&id), self.sessions.get(&id)) {
+            (Some(old_name), Some(addr)) => (old_name.clone(), addr.clone()),
+            _ => return Box::pin(fut::ready(Err(String::from("not connected")))),
+        };
+        if old_name == name {
+            return Box::pin(fut::ready(Ok(self.room_addr(&name))));
+        }
+        let player_name = self.name_of(id);
+        if self.find_by_name(&name, &player_name).is_some() {
+            let reason = format!("someone in {name} is already called {player_name}");
+            return Box::pin(fut::ready(Err(reason)));
+        }
+        let new_room = self.room_addr(&name);
+        // the remove is queued before a possible close, so the room hands the entity back before stopping
+        let removed = self
+            .room_addr(&old_name)
+            .send(messages::RemovePlayerMessage { id });
+        self.session_rooms.insert(id, name.clone());
+        self.system_message(&old_name, format!("{player_name} left"));
+        self.close_room_if_empty(&old_name);
+
+        // the entity (health etc) goes along, the new room gives it a fresh spot
+        Box::pin(removed.into_actor(self).map(move |res, act, _ctx| {
+            // disconnected, kicked or moved on while the old room answered, the entity would
+            // be a ghost nobody removes from the new room
+            if act.session_rooms.get(&id) != Some(&name) || !act.sessions.contains_key(&id) {
+                return Err(String::from("not connected"));
+            }
+            new_room.do_send(messages::AddPlayerMessage {
+                id,
+                name: player_name.clone(),
+                addr: session_addr.recipient(),
+                player: res.ok().flatten(),
+            });
+            act.send_chat_history(id, &name);
+            act.system_message(&name, format!("{player_name} joined"));
+            Ok(new_room)
+        }))
+    }
 
-        //println!("apply_player_input, player_id: {}", player_id);
+    /// stop the room actor if nobody is left in it
+    fn close_room_if_empty(&mut self, name: &str) {
+        if name == DEFAULT_ROOM || self.session_rooms.values().any(|r| r == name) {
+            return;
+        }
+        if let Some(addr) = self.rooms.remove(name) {
+            addr.do_send(messages::CloseRoomMessage);
+        }
+        self.chat.remove(name);
     }
 }
 
 impl Actor for Server {
     type Context = Context<Self>;
-
-    fn started(&mut self, ctx: &mut Self::Context) {
-        self.start_tick_interval(ctx)
-    }
 }
 
 impl Handler<messages::PlayerConnectMessage> for Server {
-    type Result = usize;
+    type Result = MessageResult<messages::PlayerConnectMessage>;
 
     fn handle(
         &mut self,
         msg: messages::PlayerConnectMessage,
-        _: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> Self::Result {
-        println!("Someone joined");
-
-        // notify all users in same room
-        //self.send_message("main", "Someone joined", 0);
-
-        let randvec3: [f32; 3] = self.rng.gen();
-        let pos = [(randvec3[0] - 0.5) * 16.0, 0.0, (randvec3[2] - 0.5) * 16.0];
-        // register session with random id
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
-        self.players.insert(id, Player::new(pos));
+        if let Some(token) = msg.resume {
+            match self.resume(token, &msg.addr, msg.ip, ctx) {
+                Some(connected) => return MessageResult(Some(connected)),
+                None => tracing::info!("resume token unknown or expired, making a new player"),
+            }
+        }
+        let id = match self.ids.alloc() {
+            Some(id) => id,
+            None => {
+                tracing::warn!("server is full, turning session away");
+                return MessageResult(None);
+            }
+        };
+        tracing::info!(player = id, "player joined");
+        let token = SessionToken::generate();
+        self.tokens.insert(token, id);
+        self.sessions.insert(id, msg.addr.clone());
+        if let Some(ip) = msg.ip {
+            self.ips.insert(id, ip);
+        }
         let _oldcount = self.visitor_count.fetch_add(1, Ordering::SeqCst);
 
-        /*
         // auto join session to main room
-        self.rooms
-            .entry("main".to_owned())
-            .or_insert_with(HashSet::new)
-            .insert(id);
-        */
-        //self.send_message("main", &format!("Total visitors {count}"), 0);
-
-        // send id back
-        id
+        let room = self.room_addr(DEFAULT_ROOM);
+        self.session_rooms.insert(id, DEFAULT_ROOM.to_owned());
+        room.do_send(messages::AddPlayerMessage {
+            id,
+            name: self.name_of(id),
+            addr: msg.addr.recipient(),
+            player: None,
+        });
+        self.send_chat_history(id, DEFAULT_ROOM);
+        self.system_message(DEFAULT_ROOM, format!("{} joined", self.name_of(id)));
+
+        // send id, token and room back
+        MessageResult(Some(messages::Connected {
+            id,
+            token,
+            room,
+            resumed: false,
+        }))
     }
 }
 
 impl Handler<messages::PlayerDisconnectMessage> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: messages::PlayerDisconnectMessage, _: &mut Context<Self>) {
-        println!("Someone disconnected, id:{}", msg.id);
-
-        self.sessions.remove(&msg.id);
-        self.players.remove(&msg.id);
+    fn handle(&mut self, msg: messages::PlayerDisconnectMessage, ctx: &mut Context<Self>) {
+        let token = msg.token;
+        let id = match self.tokens.get(&token) {
+            Some(id) => *id,
+            None => return,
+        };
+        // a session can report its disconnect more than once, only the first one counts
+        if self.sessions.remove(&id).is_none() {
+            return;
+        }
         let _oldcount = self.visitor_count.fetch_sub(1, Ordering::SeqCst);
 
-        /*
-        let mut rooms: Vec<String> = Vec::new();
-        // remove session from all rooms
-        if self.sessions.remove(&msg.id).is_some() {
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
+        if self.resume_grace.is_zero() {
+            self.remove_player(token, "left");
+            return;
         }
-        */
-
-        // send message to other users
-        /*
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+        tracing::info!(
+            player = id,
+            grace = ?self.resume_grace,
+            "connection dropped, keeping the player for a resume"
+        );
+        if let Some(addr) = self.session_rooms.get(&id).and_then(|n| self.rooms.get(n)) {
+            addr.do_send(messages::DetachSessionMessage { id });
         }
-        */
-    }
-}
-
-impl Handler<messages::PlayerInput> for Server {
-    type Result = ();
-
-    fn handle(&mut self, msg: messages::PlayerInput, _: &mut Context<Self>) {
-        self.apply_player_input(msg.id, msg)
+        let handle = ctx.run_later(self.resume_grace, move |act, _| {
+            act.remove_player(token, "left")
+        });
+        self.detached.insert(id, handle);
     }
 }
 
@@ -191,12 +485,17 @@ impl Handler<messages::ListRooms> for Server {
 }
 
 impl Handler<messages::PlayerJoinRoomMessage> for Server {
-    type Result = ();
-
-    fn handle(&mut self, _msg: messages::PlayerJoinRoomMessage, _: &mut Context<Self>) {
-        //let messages::PlayerJoinRoomMessage { id, name } = msg;
+    type Result = ResponseActFuture<Self, Result<Addr<Room>, String>>;
 
-        //self.send_message(&name, "Someone connected", id);
+    fn handle(
+        &mut self,
+        msg: messages::PlayerJoinRoomMessage,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        match self.id_of_token(&msg.token) {
+            Some(id) => self.move_player(id, msg.name),
+            None => Box::pin(fut::ready(Err(String::from("not connected")))),
+        }
     }
 }
 
@@ -204,10 +503,447 @@ impl Handler<messages::ChatMessage> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: messages::ChatMessage, _: &mut Context<Self>) {
-        let s = format!("chat {} {}", msg.id, msg.text);
-        self.broadcast(&s);
-        //let messages::PlayerJoinRoomMessage { id, name } = msg;
+        let (id, room) = match self.id_of_token(&msg.token) {
+            Some(id) => match self.session_rooms.get(&id) {
+                Some(room) => (id, room.clone()),
+                None => return,
+            },
+            None => return,
+        };
+        if self.refuse_muted(id, "chat") {
+            return;
+        }
+        let chat = messages::ServerMessage::Chat {
+            id,
+            name: self.name_of(id),
+            text: self.filter.apply(&msg.text),
+        };
+        self.say_in_room(&room, chat);
+    }
+}
+
+impl Handler<messages::WhisperMessage> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::WhisperMessage, _: &mut Context<Self>) {
+        if matches!(self.id_of_token(&msg.token), Some(id) if self.refuse_muted(id, "whisper")) {
+            return;
+        }
+        let (id, room, addr) = match self.id_of_token(&msg.token) {
+            Some(id) => match (self.session_rooms.get(&id), self.sessions.get(&id)) {
+                (Some(room), Some(addr)) => (id, room, addr),
+                _ => return,
+            },
+            None => return,
+        };
+        let to = self
+            .find_by_name(room, &msg.to)
+            .and_then(|other| Some((other, self.sessions.get(&other)?)));
+        match to {
+            Some((to_id, to)) => {
+                let whisper = messages::ServerMessage::Whisper {
+                    id,
+                    name: self.name_of(id),
+                    to: msg.to,
+                    text: self.filter.apply(&msg.text),
+                };
+                // echo it so the client can show what it said
+                if to_id != id {
+                    addr.do_send(whisper.clone());
+                }
+                to.do_send(whisper);
+            }
+            None => addr.do_send(messages::ServerMessage::Error {
+                code: messages::ErrorCode::NoSuchPlayer,
+                rejected_kind: Some(String::from("whisper")),
+                detail: format!("nobody called {} in this room", msg.to),
+            }),
+        }
+    }
+}
+
+impl Handler<messages::SetNameMessage> for Server {
+    type Result = Result<(), NameError>;
+
+    fn handle(&mut self, msg: messages::SetNameMessage, _: &mut Context<Self>) -> Self::Result {
+        let (id, room) = match self.id_of_token(&msg.token) {
+            Some(id) => match self.session_rooms.get(&id) {
+                Some(room) => (id, room.clone()),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        names::validate(&msg.name)?;
+        if self.filter.matches(&msg.name) {
+            return Err(NameError::Reserved);
+        }
+        match self.find_by_name(&room, &msg.name) {
+            // changing only the case of your own name is fine
+            Some(other) if other != id => return Err(NameError::Taken),
+            _ => (),
+        }
+
+        let old = self.name_of(id);
+        self.names.insert(id, msg.name.clone());
+        if let Some(addr) = self.rooms.get(&room) {
+            addr.do_send(messages::PlayerNameMessage {
+                id,
+                name: msg.name.clone(),
+            });
+        }
+        self.system_message(&room, format!("{old} is now {}", msg.name));
+        Ok(())
+    }
+}
+
+impl Handler<messages::ModerateMessage> for Server {
+    type Result = Result<String, String>;
+
+    fn handle(&mut self, msg: messages::ModerateMessage, _: &mut Context<Self>) -> Self::Result {
+        use messages::ModAction;
+
+        let id = msg.player_id;
+        let (name, room) = match self.session_rooms.get(&id) {
+            Some(room) => (self.name_of(id), room.clone()),
+            None => return Err(format!("no player {id}")),
+        };
+        let done = match msg.action {
+            ModAction::Mute(duration) => {
+                self.muted.insert(id, Instant::now() + duration);
+                format!("muted {name} for {}s", duration.as_secs())
+            }
+            ModAction::Unmute => {
+                self.muted.remove(&id);
+                format!("unmuted {name}")
+            }
+            ModAction::Kick { reason } => {
+                let why = match reason.is_empty() {
+                    true => String::from("kicked"),
+                    false => format!("kicked: {reason}"),
+                };
+                self.kick(id, &why);
+                format!("kicked {name}")
+            }
+            ModAction::Ban { ip: false, .. } => {
+                return Err(String::from(
+                    "a ban without the address ends when the client reconnects, kick instead",
+                ))
+            }
+            ModAction::Ban {
+                reason, duration, ..
+            } => {
+                if let Err(e) = self.ban(id, reason, duration) {
+                    return Err(format!("cant ban {name}: {e}"));
+                }
+                match duration {
+                    Some(d) => format!("banned {name} for {}s", d.as_secs()),
+                    None => format!("banned {name}"),
+                }
+            }
+        };
+        self.audit
+            .record(&room, id, &format!("{done} by {}", msg.by));
+        Ok(done)
+    }
+}
+
+impl Handler<messages::CheckBanMessage> for Server {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: messages::CheckBanMessage, _: &mut Context<Self>) -> Self::Result {
+        let ban = self.bans.find(&BanTarget::Ip(msg.ip?))?;
+        Some(ban.reason.clone())
+    }
+}
+
+impl Handler<messages::ListRoomInfo> for Server {
+    type Result = ResponseFuture<Vec<messages::RoomInfo>>;
+
+    fn handle(&mut self, _: messages::ListRoomInfo, _: &mut Context<Self>) -> Self::Result {
+        let rooms: Vec<Addr<Room>> = self.rooms.values().cloned().collect();
+        Box::pin(async move {
+            let mut infos = Vec::with_capacity(rooms.len());
+            for room in rooms {
+                // a room that closed in the meantime is just left out
+                if let Ok(info) = room.send(messages::RoomInfoMessage).await {
+                    infos.push(info);
+                }
+            }
+            infos
+        })
+    }
+}
+
+impl Handler<messages::AnnounceMessage> for Server {
+    type Result = Result<usize, String>;
+
+    fn handle(&mut self, msg: messages::AnnounceMessage, _: &mut Context<Self>) -> Self::Result {
+        let rooms: Vec<String> = match msg.room {
+            Some(room) if self.rooms.contains_key(&room) => vec![room],
+            Some(room) => return Err(format!("no room {room}")),
+            None => self.rooms.keys().cloned().collect(),
+        };
+        for room in &rooms {
+            self.system_message(room, msg.text.clone());
+        }
+        Ok(rooms.len())
+    }
+}
+
+impl Handler<messages::CreateRoomMessage> for Server {
+    type Result = bool;
+
+    fn handle(&mut self, msg: messages::CreateRoomMessage, _: &mut Context<Self>) -> Self::Result {
+        if self.rooms.contains_key(&msg.name) {
+            return false;
+        }
+        self.room_addr(&msg.name);
+        true
+    }
+}
+
+impl Handler<messages::ShutRoomMessage> for Server {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: messages::ShutRoomMessage, ctx: &mut Context<Self>) -> Self::Result {
+        if msg.name == DEFAULT_ROOM {
+            return Err(format!(
+                "{DEFAULT_ROOM} is where everyone else goes, it cant be closed"
+            ));
+        }
+        if !self.rooms.contains_key(&msg.name) {
+            return Err(format!("no room {}", msg.name));
+        }
+        let ids: Vec<usize> = self
+            .session_rooms
+            .iter()
+            .filter(|(_, room)| **room == msg.name)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            // nobody to move for a player waiting on a resume
+            if !self.sessions.contains_key(&id) {
+                if let Some(token) = self.token_of(id) {
+                    self.remove_player(token, "left");
+                }
+                continue;
+            }
+            // names are only unique per room, so someone may have to go back to player<id>
+            if self.find_by_name(DEFAULT_ROOM, &self.name_of(id)).is_some() {
+                self.names.remove(&id);
+            }
+            let moved = self
+                .move_player(id, DEFAULT_ROOM.to_owned())
+                .map(move |res, act, _| {
+                    if let (Ok(addr), Some(session)) = (res, act.sessions.get(&id)) {
+                        session.do_send(messages::RoomChangedMessage {
+                            room: DEFAULT_ROOM.to_owned(),
+                            addr,
+                        });
+                    }
+                });
+            ctx.spawn(moved);
+        }
+        self.close_room_if_empty(&msg.name);
+        Ok(())
+    }
+}
+
+impl Handler<messages::GetSettingsMessage> for Server {
+    type Result = MessageResult<messages::GetSettingsMessage>;
+
+    fn handle(&mut self, _: messages::GetSettingsMessage, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.config.settings())
+    }
+}
+
+impl Handler<messages::UpdateSettingsMessage> for Server {
+    type Result = Result<Settings, String>;
+
+    fn handle(
+        &mut self,
+        msg: messages::UpdateSettingsMessage,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        let config = self
+            .config
+            .with_settings(&msg.settings)
+            .map_err(|e| e.to_string())?;
+        self.room_config = RoomConfig::new(&config);
+        for addr in self.rooms.values() {
+            addr.do_send(messages::RoomConfigMessage {
+                config: self.room_config,
+            });
+        }
+        if config.tick_ms != self.config.tick_ms {
+            for addr in self.sessions.values() {
+                addr.do_send(messages::TickDurationMessage {
+                    tick_duration: config.tick_duration(),
+                });
+            }
+        }
+        self.config = config;
+        Ok(self.config.settings())
+    }
+}
+
+impl Handler<messages::StatsMessage> for Server {
+    type Result = ResponseFuture<metrics::Stats>;
+
+    fn handle(&mut self, msg: messages::StatsMessage, _: &mut Context<Self>) -> Self::Result {
+        let server_wait = msg.sent.elapsed();
+        // every room is asked before waiting on any, and a room stuck in a tick never
+        // answers so it is not waited on longer than a stall
+        let sent = Instant::now();
+        let timeout = self.config.max_tick_stall();
+        let requests: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|(name, addr)| {
+                let request = addr.send(messages::RoomStatsMessage { sent });
+                (name.clone(), request.timeout(timeout))
+            })
+            .collect();
+        Box::pin(async move {
+            let mut stats = metrics::Stats {
+                server_wait,
+                rooms: Vec::with_capacity(requests.len()),
+                unresponsive: Vec::new(),
+            };
+            for (name, request) in requests {
+                match request.await {
+                    Ok(room_stats) => stats.rooms.push(room_stats),
+                    Err(MailboxError::Timeout) => stats.unresponsive.push(name),
+                    // closed while the scrape ran
+                    Err(MailboxError::Closed) => {}
+                }
+            }
+            stats
+        })
+    }
+}
+
+impl Handler<messages::ShutdownMessage> for Server {
+    type Result = ResponseFuture<()>;
+
+    fn handle(&mut self, msg: messages::ShutdownMessage, _: &mut Context<Self>) -> Self::Result {
+        tracing::info!(sessions = self.sessions.len(), "shutting down");
+        let reconnect_in_ms = msg.reconnect_in.as_millis() as u64;
+        for addr in self.sessions.values() {
+            addr.do_send(messages::ServerMessage::Shutdown { reconnect_in_ms });
+            addr.do_send(messages::CloseSessionMessage {
+                why: Disconnect::Shutdown,
+                code: CloseCode::Away,
+                reason: String::from("server shutting down"),
+            });
+        }
+        if let Err(e) = self.bans.save() {
+            tracing::error!(error = %e, "failed to save bans");
+        }
+        let audit = self.audit.clone();
+        let timeout = self.config.max_tick_stall();
+        let flushes: Vec<_> = self
+            .rooms
+            .values()
+            .map(|room| room.send(messages::FlushRoomMessage).timeout(timeout))
+            .collect();
+        Box::pin(async move {
+            for flush in flushes {
+                let _ = flush.await;
+            }
+            audit.flush().await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: [u8; 4] = [203, 0, 113, 7];
+
+    /// a server with player 1 connected from IP, without a websocket behind it
+    fn start(bans_file: &std::path::Path) -> Addr<Server> {
+        let _ = std::fs::remove_file(bans_file);
+        let config = Config {
+            room_threads: 1,
+            bans_file: bans_file.to_str().unwrap().to_owned(),
+            ..Config::default()
+        };
+        let bans = BanList::load(&config.bans_file).unwrap();
+        let audit = AuditLog::open(None).unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let metrics = Arc::new(Metrics::new(config.tick_duration()));
+        Server::create(move |_| {
+            let mut server = Server::new(count, &config, audit, bans, metrics);
+            let id = server.ids.alloc().unwrap();
+            assert_eq!(id, 1);
+            server.tokens.insert(SessionToken::generate(), id);
+            server.session_rooms.insert(id, DEFAULT_ROOM.to_owned());
+            server.ips.insert(id, IpAddr::from(IP));
+            server
+        })
+    }
+
+    fn moderate(action: messages::ModAction) -> messages::ModerateMessage {
+        messages::ModerateMessage {
+            by: String::from("test"),
+            player_id: 1,
+            action,
+        }
+    }
+
+    /// what a new connection from the address without a resume token is told
+    async fn reconnect(srv: &Addr<Server>, ip: [u8; 4]) -> Option<String> {
+        srv.send(messages::CheckBanMessage {
+            ip: Some(IpAddr::from(ip)),
+        })
+        .await
+        .unwrap()
+    }
+
+    fn bans_file(test: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bans-{test}-{}.json", std::process::id()))
+    }
+
+    #[actix::test]
+    async fn banned_client_cant_reconnect() {
+        let path = bans_file("ban");
+        let srv = start(&path);
+        assert_eq!(reconnect(&srv, IP).await, None);
+
+        let ban = messages::ModAction::Ban {
+            reason: String::from("cheating"),
+            duration: None,
+            ip: true,
+        };
+        srv.send(moderate(ban)).await.unwrap().unwrap();
+        assert_eq!(reconnect(&srv, IP).await.as_deref(), Some("cheating"));
+        assert_eq!(reconnect(&srv, [203, 0, 113, 8]).await, None);
+
+        // and after a restart
+        let bans = BanList::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bans.find(&BanTarget::Ip(IpAddr::from(IP))).is_some());
+    }
 
-        //self.send_message(&name, "Someone connected", id);
+    #[actix::test]
+    async fn ban_without_the_address_is_refused() {
+        let path = bans_file("no-ip");
+        let srv = start(&path);
+        let ban = messages::ModAction::Ban {
+            reason: String::new(),
+            duration: None,
+            ip: false,
+        };
+        assert!(srv.send(moderate(ban)).await.unwrap().is_err());
+        // refused before kicking, the player is still there
+        let kick = messages::ModAction::Kick {
+            reason: String::new(),
+        };
+        srv.send(moderate(kick)).await.unwrap().unwrap();
+        // a kicked client may come back
+        assert_eq!(reconnect(&srv, IP).await, None);
+        let _ = std::fs::remove_file(&path);
     }
 }
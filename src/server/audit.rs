@@ -0,0 +1,118 @@
+use actix::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where suspicious client behaviour is written down, one line per event.
+/// Shared by all rooms, so it can be cloned into each of them. Lines go to a writer on
+/// its own thread, a slow disk holds up the writer and not the tick of the room.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    /// None writes to stdout
+    writer: Option<Addr<AuditWriter>>,
+}
+
+impl AuditLog {
+    /// append to the file at path, or stdout if there is none
+    pub fn open(path: Option<&str>) -> io::Result<Self> {
+        let writer = match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let file = Arc::new(file);
+                // one thread, so lines are written in the order they were recorded
+                Some(SyncArbiter::start(1, move || AuditWriter {
+                    file: file.clone(),
+                }))
+            }
+            None => None,
+        };
+        Ok(Self { writer })
+    }
+
+    pub fn record(&self, room: &str, id: usize, what: &str) {
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        match &self.writer {
+            Some(writer) => writer.do_send(Line(format!("{ms} room={room} player={id} {what}"))),
+            None => tracing::warn!(target: "audit", room, player = id, "{what}"),
+        }
+    }
+
+    /// resolves once everything recorded so far is on disk
+    pub async fn flush(self) {
+        if let Some(writer) = self.writer {
+            if writer.send(Flush).await.is_err() {
+                tracing::error!("audit writer is gone, cant flush the audit log");
+            }
+        }
+    }
+}
+
+/// Owns the audit file, the only one writing to it
+#[derive(Debug)]
+pub struct AuditWriter {
+    file: Arc<File>,
+}
+
+impl Actor for AuditWriter {
+    type Context = SyncContext<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Line(String);
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Flush;
+
+impl Handler<Line> for AuditWriter {
+    type Result = ();
+
+    fn handle(&mut self, Line(line): Line, _: &mut Self::Context) {
+        let mut file = &*self.file;
+        if let Err(e) = writeln!(file, "{line}") {
+            tracing::error!(error = %e, line, "failed to write audit log");
+        }
+    }
+}
+
+impl Handler<Flush> for AuditWriter {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, _: &mut Self::Context) {
+        if let Err(e) = self.file.sync_all() {
+            tracing::error!(error = %e, "failed to flush audit log");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix::test]
+    async fn lines_are_on_disk_once_flushed() {
+        let path = std::env::temp_dir().join(format!("audit-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let audit = AuditLog::open(path.to_str()).unwrap();
+        let room = audit.clone();
+        for id in 0..100 {
+            room.record("main", id, "held kick");
+        }
+        audit.flush().await;
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 100);
+        for (id, line) in lines.iter().enumerate() {
+            assert!(
+                line.ends_with(&format!(" room=main player={id} held kick")),
+                "{line}"
+            );
+        }
+    }
+}
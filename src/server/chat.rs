@@ -0,0 +1,33 @@
+use crate::messages::ServerMessage;
+use std::collections::VecDeque;
+
+/// The last things said in a room, replayed to players joining it
+#[derive(Debug)]
+pub struct ChatHistory {
+    max_len: usize,
+    messages: VecDeque<ServerMessage>,
+}
+
+impl ChatHistory {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            messages: VecDeque::with_capacity(max_len),
+        }
+    }
+
+    pub fn push(&mut self, msg: ServerMessage) {
+        if self.max_len == 0 {
+            return;
+        }
+        if self.messages.len() == self.max_len {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(msg);
+    }
+
+    /// oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &ServerMessage> {
+        self.messages.iter()
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::{Duration, Instant};
+
+/// a room this far behind skips ticks instead of trying to catch up on all of them
+pub const MAX_CATCH_UP_TICKS: u64 = 5;
+
+/// Fixed timestep simulation clock. Tick n is due at epoch + (n - epoch_tick) * tick_duration,
+/// so late wakeups dont accumulate into drift, they just mean more ticks are due.
+#[derive(Debug)]
+pub struct SimClock {
+    tick: u64,
+    epoch: Instant,
+    /// the tick that was current at epoch, moves when the tick duration changes
+    epoch_tick: u64,
+    tick_duration: Duration,
+}
+
+impl SimClock {
+    pub fn new(tick_duration: Duration) -> Self {
+        Self {
+            tick: 0,
+            epoch: Instant::now(),
+            epoch_tick: 0,
+            tick_duration,
+        }
+    }
+
+    /// the last tick that was simulated
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn tick_duration(&self) -> Duration {
+        self.tick_duration
+    }
+
+    pub fn advance(&mut self) {
+        self.tick += 1;
+    }
+
+    /// how many ticks should have been simulated by now but havent
+    pub fn due(&self, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(self.epoch);
+        let target = self.epoch_tick + (elapsed.as_nanos() / self.tick_duration.as_nanos()) as u64;
+        target.saturating_sub(self.tick)
+    }
+
+    /// time left until the next tick is due
+    pub fn until_next(&self, now: Instant) -> Duration {
+        let next = self.epoch + self.tick_duration * (self.tick + 1 - self.epoch_tick) as u32;
+        next.saturating_duration_since(now)
+    }
+
+    /// give up on n due ticks, moving the schedule forward instead of simulating them
+    pub fn skip(&mut self, n: u64) {
+        self.epoch += self.tick_duration * n as u32;
+    }
+
+    /// tick at another rate from now on, without jumping ahead or back
+    pub fn set_tick_duration(&mut self, tick_duration: Duration, now: Instant) {
+        self.epoch = now;
+        self.epoch_tick = self.tick;
+        self.tick_duration = tick_duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    /// a clock that has simulated every tick due at start
+    fn new_clock() -> (SimClock, Instant) {
+        let clock = SimClock::new(TICK);
+        let start = clock.epoch;
+        (clock, start)
+    }
+
+    fn run(clock: &mut SimClock, now: Instant) {
+        for _ in 0..clock.due(now) {
+            clock.advance();
+        }
+    }
+
+    #[test]
+    fn nothing_is_due_before_the_first_tick() {
+        let (clock, start) = new_clock();
+        assert_eq!(clock.due(start), 0);
+        assert_eq!(clock.due(start + TICK - Duration::from_nanos(1)), 0);
+        assert_eq!(clock.due(start + TICK), 1);
+        assert_eq!(clock.until_next(start + TICK / 4), TICK * 3 / 4);
+    }
+
+    #[test]
+    fn a_late_wakeup_runs_the_missed_ticks_and_keeps_the_schedule() {
+        let (mut clock, start) = new_clock();
+        // woke up at 3.5 ticks instead of 1
+        let late = start + TICK * 7 / 2;
+        assert_eq!(clock.due(late), 3);
+        run(&mut clock, late);
+        assert_eq!(clock.tick(), 3);
+        // the next tick is still at 4, the lateness did not push it back
+        assert_eq!(clock.until_next(late), TICK / 2);
+        assert_eq!(clock.due(start + TICK * 4), 1);
+    }
+
+    #[test]
+    fn ticks_past_the_catch_up_limit_are_skipped_not_run_later() {
+        let (mut clock, start) = new_clock();
+        let stalled = start + TICK * 50;
+        let due = clock.due(stalled);
+        assert_eq!(due, 50);
+        // what the room does when it falls this far behind
+        clock.skip(due - MAX_CATCH_UP_TICKS);
+        assert_eq!(clock.due(stalled), MAX_CATCH_UP_TICKS);
+        run(&mut clock, stalled);
+        assert_eq!(clock.tick(), MAX_CATCH_UP_TICKS);
+        assert_eq!(clock.due(stalled), 0);
+        assert_eq!(clock.until_next(stalled), TICK);
+    }
+
+    #[test]
+    fn changing_the_tick_duration_does_not_jump_ahead() {
+        let (mut clock, start) = new_clock();
+        let now = start + TICK * 20;
+        run(&mut clock, now);
+        assert_eq!(clock.tick(), 20);
+
+        // counted from the old epoch, the 200ms so far would be 200 ticks of 1ms
+        clock.set_tick_duration(TICK / 10, now);
+        assert_eq!(clock.tick(), 20);
+        assert_eq!(clock.due(now), 0);
+        assert_eq!(clock.until_next(now), TICK / 10);
+        assert_eq!(clock.due(now + TICK), 10);
+
+        // slowing down starts the longer wait from now
+        let (mut clock, start) = new_clock();
+        let now = start + TICK * 20;
+        run(&mut clock, now);
+        clock.set_tick_duration(TICK * 10, now);
+        assert_eq!(clock.due(now + TICK * 9), 0);
+        assert_eq!(clock.due(now + TICK * 10), 1);
+    }
+}
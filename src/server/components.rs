@@ -1,13 +1,11 @@
 use gl_matrix::common::{Mat4, Quat, Vec2, Vec3};
-use gl_matrix::{mat4, quat, vec2, vec3};
-use rand::{self, Rng};
+use gl_matrix::{mat4, quat, vec2};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::messages::PlayerInput;
-use crate::server::TICKS_PER_SECOND;
-
 //note to self:
 //anything in this file should be identical on server and client?
+//so it should only depend on gl_matrix, rand and serde, not on anything else in this crate
 
 //inline version
 pub fn vec2_add(out: &mut Vec2, v: &Vec2) -> Vec2 {
@@ -44,6 +42,7 @@ pub fn vec2_scale(out: &mut Vec2, k: f32) -> Vec2 {
 }
 
 //inline version
+#[allow(dead_code)]
 pub fn vec2_rotate(out: &mut Vec2, origin: &Vec2, rad: f32) -> Vec2 {
     let p0 = out[0] - origin[0];
     let p1 = out[1] - origin[1];
@@ -56,6 +55,7 @@ pub fn vec2_rotate(out: &mut Vec2, origin: &Vec2, rad: f32) -> Vec2 {
 
     *out
 }
+#[allow(dead_code)]
 pub fn vec2_rotate_around_origin(out: &mut Vec2, rad: f32) -> Vec2 {
     let p0 = out[0];
     let p1 = out[1];
@@ -96,6 +96,61 @@ pub fn quat_from_rad(out: &mut Quat, x: f32, y: f32, z: f32) {
     out[3] = cx * cy * cz + sx * sy * sz;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlayerInput {
+    /// Id of the client session
+    pub id: usize,
+    /// increases by one for every input the client sends, 0 means the client doesnt number its input
+    pub seq: u32,
+    pub step_forward: bool,
+    pub step_backward: bool,
+    pub step_left: bool,
+    pub step_right: bool,
+    pub kick: bool,
+    pub punch: bool,
+    pub run: bool,
+    pub facing_rad: f32,
+}
+
+impl PlayerInput {
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+            seq: 0,
+            step_forward: false,
+            step_backward: false,
+            step_left: false,
+            step_right: false,
+            kick: false,
+            punch: false,
+            run: false,
+            facing_rad: 0.,
+        }
+    }
+}
+
+/// size of the playing field
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Arena {
+    /// players spawn in -spawn_half_extent..spawn_half_extent on x and z
+    pub spawn_half_extent: f32,
+    /// and fall off outside -half_extent..half_extent
+    pub half_extent: f32,
+}
+
+impl Arena {
+    pub fn spawn_pos<R: Rng>(&self, rng: &mut R) -> [f32; 3] {
+        let randvec3: [f32; 3] = rng.gen();
+        let size = self.spawn_half_extent * 2.0;
+        [(randvec3[0] - 0.5) * size, 0.0, (randvec3[2] - 0.5) * size]
+    }
+
+    pub fn contains(&self, pos: &Vec3) -> bool {
+        let e = self.half_extent;
+        pos[0] >= -e && pos[0] <= e && pos[2] >= -e && pos[2] <= e
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Projectile {
     pub ticks: u32,
@@ -113,6 +168,8 @@ pub struct Player {
     pub anim_target_id: AnimTargetId,
     pub anim_ticks: u32,
     pub projectile: Option<Projectile>,
+    /// seq of the player_input that was last applied
+    pub last_input_seq: u32,
 }
 
 impl Player {
@@ -129,19 +186,19 @@ impl Player {
             anim_target_id: AnimTargetId::Idle,
             anim_ticks: 0,
             projectile: None,
+            last_input_seq: 0,
         }
     }
 
-    pub fn respawn(&mut self) {
-        let randvec3: [f32; 3] = rand::thread_rng().gen();
-        let pos = [(randvec3[0] - 0.5) * 16.0, 0.0, (randvec3[2] - 0.5) * 16.0];
-        self.transform.pos = pos;
+    pub fn respawn<R: Rng>(&mut self, arena: &Arena, rng: &mut R) {
+        self.transform.pos = arena.spawn_pos(rng);
         self.attributes.health = 100.0;
     }
 
     /// apply self.player_input all the way to self.renderable
-    pub fn apply(&mut self) {
-        let prev_anim_target_id = self.anim_target_id.clone();
+    pub fn apply(&mut self, ticks_per_second: f32) {
+        self.last_input_seq = self.player_input.seq;
+        let prev_anim_target_id = self.anim_target_id;
         let mut is_ability = false;
         self.anim_target_id = AnimTargetId::Idle;
 
@@ -152,10 +209,10 @@ impl Player {
             self.anim_target_id = AnimTargetId::Punch;
             is_ability = true;
         }
-        if is_ability == false {
-            let is_walking = self
-                .transform
-                .apply(&self.player_input, &mut self.attributes);
+        if !is_ability {
+            let is_walking =
+                self.transform
+                    .apply(&self.player_input, &mut self.attributes, ticks_per_second);
             if is_walking {
                 self.anim_target_id = AnimTargetId::Walk;
             }
@@ -167,14 +224,11 @@ impl Player {
             self.projectile = None;
         } else {
             self.anim_ticks += 1;
-            match &mut self.projectile {
-                Some(proj) => {
-                    proj.ticks += 1;
-                    if proj.ticks > proj.ticks_lifetime {
-                        self.projectile = None;
-                    }
+            if let Some(proj) = &mut self.projectile {
+                proj.ticks += 1;
+                if proj.ticks > proj.ticks_lifetime {
+                    self.projectile = None;
                 }
-                None => (),
             }
         }
     }
@@ -219,9 +273,15 @@ impl Transform {
 }
 
 impl Transform {
-    /// update velocity and position
-    pub fn apply(&mut self, player_input: &PlayerInput, attributes: &mut Attributes) -> bool {
-        let mut is_walking = false;
+    /// update velocity and position.
+    /// this is the exact step the client runs to predict its own player: starting from the
+    /// server state and applying the inputs after last_input_seq gives the same transform
+    pub fn apply(
+        &mut self,
+        player_input: &PlayerInput,
+        attributes: &mut Attributes,
+        ticks_per_second: f32,
+    ) -> bool {
         let mut v = vec2::create();
         let right = [1.0, 0.0];
         let left = [-1.0, 0.0];
@@ -229,19 +289,15 @@ impl Transform {
         let backward = [0.0, 1.0];
         if player_input.step_forward {
             vec2_add(&mut v, &forward);
-            is_walking = true;
         }
         if player_input.step_backward {
             vec2_add(&mut v, &backward);
-            is_walking = true;
         }
         if player_input.step_right {
             vec2_add(&mut v, &right);
-            is_walking = true;
         }
         if player_input.step_left {
             vec2_add(&mut v, &left);
-            is_walking = true;
         }
         let is_walking = v[0] != 0. || v[1] != 0.;
 
@@ -252,7 +308,7 @@ impl Transform {
         }
         //vec2_rotate_around_origin(&mut v, player_input.facing_rad);
         vec2_normalize(&mut v);
-        let dist_per_tick = attributes.move_speed / TICKS_PER_SECOND;
+        let dist_per_tick = attributes.move_speed / ticks_per_second;
         vec2_scale(&mut v, dist_per_tick);
 
         //update pos
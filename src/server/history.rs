@@ -0,0 +1,58 @@
+use super::components::{Player, Transform};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Where every player was at each of the last ticks, so hits can be checked against
+/// what an attacker actually saw instead of where victims are now
+#[derive(Debug)]
+pub struct TransformHistory {
+    max_len: usize,
+    ticks: VecDeque<(u64, HashMap<usize, Transform>)>,
+}
+
+impl TransformHistory {
+    pub fn new(max_rewind_ticks: u64) -> Self {
+        let max_len = max_rewind_ticks as usize + 1;
+        Self {
+            max_len,
+            ticks: VecDeque::with_capacity(max_len),
+        }
+    }
+
+    pub fn push(&mut self, tick: u64, players: &BTreeMap<usize, Player>) {
+        if self.ticks.len() == self.max_len {
+            self.ticks.pop_front();
+        }
+        let transforms = players.iter().map(|(id, p)| (*id, p.transform)).collect();
+        self.ticks.push_back((tick, transforms));
+    }
+
+    pub fn get(&self, tick: u64, id: usize) -> Option<&Transform> {
+        let oldest = self.ticks.front()?.0;
+        let i = tick.checked_sub(oldest)? as usize;
+        match self.ticks.get(i) {
+            Some((t, transforms)) if *t == tick => transforms.get(&id),
+            _ => None,
+        }
+    }
+}
+
+/// Which tick each attacker was looking at when its attack lands
+pub struct Rewind<'a> {
+    history: &'a TransformHistory,
+    view_ticks: &'a HashMap<usize, u64>,
+}
+
+impl<'a> Rewind<'a> {
+    pub fn new(history: &'a TransformHistory, view_ticks: &'a HashMap<usize, u64>) -> Self {
+        Self {
+            history,
+            view_ticks,
+        }
+    }
+
+    /// where the attacker saw the victim, None if there is nothing to rewind to
+    pub fn victim_transform(&self, attacker_id: usize, victim_id: usize) -> Option<&Transform> {
+        let tick = self.view_ticks.get(&attacker_id)?;
+        self.history.get(*tick, victim_id)
+    }
+}
@@ -0,0 +1,152 @@
+use rand::{rngs::OsRng, RngCore};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Hands out the public player ids. They are small (they go in every snapshot) and
+/// unique among connected players, and an id is not handed out again until all the
+/// others have been, so a late message for a player that left cant reach a new one.
+#[derive(Debug)]
+pub struct IdAllocator {
+    last: u16,
+    in_use: HashSet<u16>,
+}
+
+impl IdAllocator {
+    pub fn new() -> Self {
+        Self {
+            last: 0,
+            in_use: HashSet::new(),
+        }
+    }
+
+    /// a free id, None if every id is taken
+    pub fn alloc(&mut self) -> Option<usize> {
+        // 0 is never used, it is what a session has before it gets an id
+        if self.in_use.len() >= u16::MAX as usize {
+            return None;
+        }
+        let mut id = self.last;
+        loop {
+            id = id.checked_add(1).unwrap_or(1);
+            if self.in_use.insert(id) {
+                self.last = id;
+                return Some(id as usize);
+            }
+        }
+    }
+
+    pub fn free(&mut self, id: usize) {
+        if let Ok(id) = u16::try_from(id) {
+            self.in_use.remove(&id);
+        }
+    }
+}
+
+/// Secret identifying a session to the server. Unlike the player id it is never
+/// shown to other clients, so knowing someones id is not enough to act as them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionToken([u8; 16]);
+
+impl SessionToken {
+    pub fn generate() -> Self {
+        let mut bytes = [0; 16];
+        OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+}
+
+/// hex, this is how the client gets it and hands it back when resuming
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SessionToken {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // from_str_radix alone would take a + sign
+        if s.len() != 32 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(());
+        }
+        let mut bytes = [0; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| ())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// keep it out of logs
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionToken(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_not_reused_until_every_other_one_was_handed_out() {
+        let mut ids = IdAllocator::new();
+        assert_eq!(ids.alloc(), Some(1));
+        assert_eq!(ids.alloc(), Some(2));
+        assert_eq!(ids.alloc(), Some(3));
+        ids.free(2);
+        assert_eq!(ids.alloc(), Some(4));
+    }
+
+    #[test]
+    fn ids_wrap_around_past_zero_and_the_ones_in_use() {
+        let mut ids = IdAllocator::new();
+        assert_eq!(ids.alloc(), Some(1));
+        ids.last = u16::MAX - 1;
+        assert_eq!(ids.alloc(), Some(u16::MAX as usize));
+        // 0 is skipped and 1 is still taken
+        assert_eq!(ids.alloc(), Some(2));
+    }
+
+    #[test]
+    fn none_when_every_id_is_taken() {
+        let mut ids = IdAllocator::new();
+        let all: HashSet<usize> = (0..u16::MAX).map(|_| ids.alloc().unwrap()).collect();
+        assert_eq!(all.len(), u16::MAX as usize);
+        assert!(!all.contains(&0));
+        assert_eq!(ids.alloc(), None);
+        ids.free(1234);
+        assert_eq!(ids.alloc(), Some(1234));
+        assert_eq!(ids.alloc(), None);
+    }
+
+    #[test]
+    fn tokens_read_back_from_their_hex() {
+        let token = SessionToken::generate();
+        let hex = token.to_string();
+        assert_eq!(hex.len(), 32);
+        assert_eq!(hex.parse::<SessionToken>(), Ok(token));
+        assert_eq!(hex.to_uppercase().parse::<SessionToken>(), Ok(token));
+        assert_ne!(SessionToken::generate(), token);
+    }
+
+    #[test]
+    fn bad_tokens_are_refused() {
+        let hex = SessionToken::generate().to_string();
+        for bad in [
+            String::new(),
+            hex[..30].to_owned(),
+            format!("{hex}00"),
+            format!("{}zz", &hex[..30]),
+            format!("+f{}", &hex[2..]),
+            format!("é{}", &hex[2..]),
+        ] {
+            assert!(bad.parse::<SessionToken>().is_err(), "{bad}");
+        }
+    }
+}
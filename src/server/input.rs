@@ -0,0 +1,240 @@
+use super::components::PlayerInput;
+use serde::Deserialize;
+use std::collections::VecDeque;
+
+/// What to do with a new input when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// drop the oldest queued input, its attacks are carried over to the next one
+    DropOldest,
+    /// fold the new input into the newest queued one
+    Merge,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct InputQueueConfig {
+    pub max_len: usize,
+    pub overflow: OverflowPolicy,
+    /// with more than this many inputs queued (the client is sending in bursts),
+    /// two inputs are merged and consumed per tick until it is caught up
+    pub catch_up_len: usize,
+}
+
+/// Inputs of one player waiting to be applied, one per tick
+#[derive(Debug)]
+pub struct InputQueue {
+    config: InputQueueConfig,
+    inputs: VecDeque<PlayerInput>,
+    last_seq: u32,
+}
+
+impl InputQueue {
+    pub fn new(config: InputQueueConfig) -> Self {
+        Self {
+            config,
+            inputs: VecDeque::with_capacity(config.max_len),
+            last_seq: 0,
+        }
+    }
+
+    /// queue input, returns false if it was older than what is already queued
+    pub fn push(&mut self, input: PlayerInput) -> bool {
+        // 0 means the client doesnt number its input, so there is no order to check
+        if input.seq != 0 {
+            if input.seq <= self.last_seq {
+                return false;
+            }
+            self.last_seq = input.seq;
+        }
+
+        if self.inputs.len() < self.config.max_len.max(1) {
+            self.inputs.push_back(input);
+            return true;
+        }
+        match self.config.overflow {
+            OverflowPolicy::DropOldest => {
+                if let Some(dropped) = self.inputs.pop_front() {
+                    if let Some(next) = self.inputs.front_mut() {
+                        *next = merge(&dropped, next);
+                        self.inputs.push_back(input);
+                    } else {
+                        self.inputs.push_back(merge(&dropped, &input));
+                    }
+                }
+            }
+            OverflowPolicy::Merge => {
+                if let Some(newest) = self.inputs.back_mut() {
+                    *newest = merge(newest, &input);
+                }
+            }
+        }
+        true
+    }
+
+    /// the input to apply this tick, None if nothing new arrived
+    pub fn pop(&mut self) -> Option<PlayerInput> {
+        let input = self.inputs.pop_front()?;
+        if self.inputs.len() >= self.config.catch_up_len {
+            if let Some(next) = self.inputs.pop_front() {
+                return Some(merge(&input, &next));
+            }
+        }
+        Some(input)
+    }
+}
+
+/// combine two inputs into one: movement from the newer, attacks from either so a tap is never lost
+fn merge(older: &PlayerInput, newer: &PlayerInput) -> PlayerInput {
+    PlayerInput {
+        kick: older.kick || newer.kick,
+        punch: older.punch || newer.punch,
+        ..*newer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_len: usize, overflow: OverflowPolicy, catch_up_len: usize) -> InputQueue {
+        InputQueue::new(InputQueueConfig {
+            max_len,
+            overflow,
+            catch_up_len,
+        })
+    }
+
+    fn input(seq: u32) -> PlayerInput {
+        PlayerInput {
+            seq,
+            facing_rad: seq as f32,
+            ..PlayerInput::new()
+        }
+    }
+
+    fn drain(queue: &mut InputQueue) -> Vec<PlayerInput> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn inputs_older_than_the_last_one_are_refused() {
+        let mut queue = queue(8, OverflowPolicy::DropOldest, 8);
+        assert!(queue.push(input(1)));
+        assert!(queue.push(input(3)));
+        assert!(!queue.push(input(3)));
+        assert!(!queue.push(input(2)));
+        // unnumbered input has no order to check
+        assert!(queue.push(input(0)));
+        let seqs: Vec<u32> = drain(&mut queue).iter().map(|i| i.seq).collect();
+        assert_eq!(seqs, [1, 3, 0]);
+    }
+
+    #[test]
+    fn drop_oldest_carries_the_attacks_of_the_dropped_input() {
+        let mut queue = queue(2, OverflowPolicy::DropOldest, 8);
+        queue.push(PlayerInput {
+            kick: true,
+            ..input(1)
+        });
+        queue.push(input(2));
+        queue.push(input(3));
+        let popped = drain(&mut queue);
+        assert_eq!(popped.len(), 2);
+        assert_eq!(
+            popped[0],
+            PlayerInput {
+                kick: true,
+                ..input(2)
+            }
+        );
+        assert_eq!(popped[1], input(3));
+    }
+
+    #[test]
+    fn drop_oldest_with_room_for_one_merges_into_the_new_input() {
+        let mut queue = queue(1, OverflowPolicy::DropOldest, 8);
+        queue.push(PlayerInput {
+            punch: true,
+            ..input(1)
+        });
+        queue.push(input(2));
+        assert_eq!(
+            drain(&mut queue),
+            [PlayerInput {
+                punch: true,
+                ..input(2)
+            }]
+        );
+    }
+
+    #[test]
+    fn merge_folds_new_inputs_into_the_newest_queued_one() {
+        let mut queue = queue(2, OverflowPolicy::Merge, 8);
+        for seq in 1..=2 {
+            queue.push(input(seq));
+        }
+        queue.push(PlayerInput {
+            punch: true,
+            ..input(3)
+        });
+        queue.push(input(4));
+        let popped = drain(&mut queue);
+        assert_eq!(popped.len(), 2);
+        assert_eq!(popped[0], input(1));
+        // movement from the newest, the tap from the one folded in before it
+        assert_eq!(
+            popped[1],
+            PlayerInput {
+                punch: true,
+                ..input(4)
+            }
+        );
+    }
+
+    #[test]
+    fn a_backlog_is_consumed_two_at_a_time_until_caught_up() {
+        let mut queue = queue(8, OverflowPolicy::DropOldest, 2);
+        for seq in 1..=5 {
+            queue.push(PlayerInput {
+                kick: seq == 3,
+                ..input(seq)
+            });
+        }
+        let popped = drain(&mut queue);
+        assert_eq!(
+            popped,
+            [
+                input(2),
+                PlayerInput {
+                    kick: true,
+                    ..input(4)
+                },
+                input(5)
+            ]
+        );
+    }
+
+    #[test]
+    fn a_tap_is_never_lost() {
+        for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::Merge] {
+            for tap in 1..=20 {
+                let mut queue = queue(3, overflow, 2);
+                let mut popped = Vec::new();
+                // the room pops once a tick, but the client sends four inputs in that time
+                for burst in 0..5 {
+                    for seq in burst * 4 + 1..=burst * 4 + 4 {
+                        queue.push(PlayerInput {
+                            kick: seq == tap,
+                            ..input(seq)
+                        });
+                    }
+                    popped.extend(queue.pop());
+                }
+                popped.extend(drain(&mut queue));
+                let kicks = popped.iter().filter(|i| i.kick).count();
+                assert_eq!(kicks, 1, "{overflow:?}, tap in input {tap}");
+            }
+        }
+    }
+}
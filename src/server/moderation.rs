@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Words replaced in chat. Entries are `word` (replaced by stars) or `word=replacement`,
+/// matched as whole words without case.
+#[derive(Clone, Debug)]
+pub struct WordFilter {
+    words: Vec<(String, Option<String>)>,
+}
+
+impl WordFilter {
+    pub fn new(entries: &[String]) -> Self {
+        let words = entries
+            .iter()
+            .filter_map(|entry| {
+                let (word, replacement) = match entry.split_once('=') {
+                    Some((word, replacement)) => (word, Some(replacement.trim().to_owned())),
+                    None => (entry.as_str(), None),
+                };
+                let word = word.trim().to_lowercase();
+                (!word.is_empty()).then_some((word, replacement))
+            })
+            .collect();
+        Self { words }
+    }
+
+    fn replacement(&self, word: &str) -> Option<String> {
+        let (_, replacement) = self.words.iter().find(|(w, _)| w == &word.to_lowercase())?;
+        Some(match replacement {
+            Some(replacement) => replacement.clone(),
+            None => "*".repeat(word.chars().count()),
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        if self.words.is_empty() {
+            return text.to_owned();
+        }
+        let mut out = String::with_capacity(text.len());
+        let mut word = String::new();
+        for c in text.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            if !word.is_empty() {
+                out += &self.replacement(&word).unwrap_or_else(|| word.clone());
+                word.clear();
+            }
+            out.push(c);
+        }
+        out.pop();
+        out
+    }
+
+    /// true if the filter would change any word of it
+    pub fn matches(&self, text: &str) -> bool {
+        text.split(|c: char| !c.is_alphanumeric())
+            .any(|word| self.replacement(word).is_some())
+    }
+}
+
+/// What a ban holds on to. Only the address: a kick spends the resume token, and a client
+/// reconnecting without one is a new player
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum BanTarget {
+    Ip(IpAddr),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    /// unix seconds, None is for good
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    fn expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/// Bans, kept in a json file so they outlive restarts
+#[derive(Debug)]
+pub struct BanList {
+    path: String,
+    bans: Vec<Ban>,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl BanList {
+    /// read the bans in the file at path, none if it doesnt exist yet
+    pub fn load(path: &str) -> io::Result<Self> {
+        let bans = match std::fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {e}")))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            bans,
+        })
+    }
+
+    /// write to a temporary file first so a crash cant leave half a ban list
+    pub fn save(&mut self) -> io::Result<()> {
+        let now = unix_now();
+        self.bans.retain(|ban| !ban.expired(now));
+        let text = serde_json::to_string_pretty(&self.bans)?;
+        let tmp = format!("{}.tmp", self.path);
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    pub fn add(&mut self, ban: Ban) {
+        self.bans.retain(|b| b.target != ban.target);
+        self.bans.push(ban);
+    }
+
+    /// the ban that applies to the target, if there is one
+    pub fn find(&self, target: &BanTarget) -> Option<&Ban> {
+        let now = unix_now();
+        self.bans
+            .iter()
+            .find(|ban| !ban.expired(now) && ban.target == *target)
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+
+pub const MIN_LEN: usize = 3;
+pub const MAX_LEN: usize = 16;
+
+/// nobody gets to look like staff or like the server
+const RESERVED: [&str; 7] = [
+    "admin",
+    "administrator",
+    "console",
+    "mod",
+    "moderator",
+    "server",
+    "system",
+];
+
+/// Why a display name was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameError {
+    Length,
+    Charset,
+    Reserved,
+    /// someone in the room already goes by it
+    Taken,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Length => write!(f, "names are {MIN_LEN} to {MAX_LEN} characters"),
+            NameError::Charset => write!(f, "names are letters, digits, _ and -"),
+            NameError::Reserved => write!(f, "that name is reserved"),
+            NameError::Taken => write!(f, "someone in the room already has that name"),
+        }
+    }
+}
+
+/// the name a player has until it picks one
+pub fn default_name(id: usize) -> String {
+    format!("player{id}")
+}
+
+/// check a name on its own, whether someone else has it is up to the caller
+pub fn validate(name: &str) -> Result<(), NameError> {
+    if !(MIN_LEN..=MAX_LEN).contains(&name.len()) {
+        return Err(NameError::Length);
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(NameError::Charset);
+    }
+    let lower = name.to_ascii_lowercase();
+    // player<digits> would pass for someone elses default name
+    let looks_default = matches!(
+        lower.strip_prefix("player"),
+        Some(rest) if rest.chars().all(|c| c.is_ascii_digit())
+    );
+    if looks_default || RESERVED.contains(&lower.as_str()) {
+        return Err(NameError::Reserved);
+    }
+    Ok(())
+}
+
+/// names differing only in case are the same name, so nobody can pose as Alice as alice
+pub fn same(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordinary_names_are_accepted() {
+        for name in [
+            "bob",
+            "Alice_99",
+            "x-y-z",
+            "abcdefghijklmnop",
+            "player_1",
+            "players2",
+            "admins",
+        ] {
+            assert_eq!(validate(name), Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn names_are_min_to_max_len_long() {
+        assert_eq!(validate("ab"), Err(NameError::Length));
+        assert_eq!(validate(""), Err(NameError::Length));
+        assert_eq!(validate("abcdefghijklmnopq"), Err(NameError::Length));
+        // counted in bytes, 9 characters but 18 bytes
+        assert_eq!(validate("ééééééééé"), Err(NameError::Length));
+    }
+
+    #[test]
+    fn names_are_letters_digits_underscores_and_dashes() {
+        for name in ["bob smith", "bob!", "<b>bob", "bob\n", "bobé", "bob.exe"] {
+            assert_eq!(validate(name), Err(NameError::Charset), "{name}");
+        }
+    }
+
+    #[test]
+    fn staff_and_default_names_are_reserved() {
+        for name in [
+            "admin",
+            "Admin",
+            "MODERATOR",
+            "server",
+            "System",
+            "player",
+            "player7",
+            "Player123",
+        ] {
+            assert_eq!(validate(name), Err(NameError::Reserved), "{name}");
+        }
+        assert_eq!(validate(&default_name(42)), Err(NameError::Reserved));
+    }
+
+    #[test]
+    fn names_differing_in_case_are_the_same() {
+        assert!(same("Alice", "aLICE"));
+        assert!(!same("Alice", "Alice2"));
+    }
+}
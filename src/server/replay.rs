@@ -0,0 +1,841 @@
+//! Replay files, one per room, to look at a fight again after the fact.
+//!
+//! A room records everything that goes into its `World` from outside: the seed of its rng,
+//! the inputs applied each tick with the ticks attackers were looking at, players joining,
+//! leaving or being stood still, and config changes. Stepping a new world through the
+//! records simulates the same ticks again.
+//!
+//! Little endian, a header and then records until the end of the file:
+//! ```text
+//! header: 8 bytes MAGIC, u8 version, u64 seed, u64 start_tick, u8 name_len + room name, config
+//! config: u32 tick_us, f32 spawn_half_extent, f32 half_extent, u32 max_rewind_ticks
+//! input:  u32 seq, u8 buttons (bit order as in PlayerInput), f32 facing_rad
+//! records start with a u8 kind:
+//!   TICK=0:   u32 ticks since the last tick record (or start_tick), u16 n_inputs, then
+//!             n_inputs x (u16 id, input), u16 n_views, then n_views x (u16 id, u32 ticks behind)
+//!   JOIN=1:   u16 id, u8 name_len + name, u8 has_player, if has_player: u32 len + the
+//!             player from another room as json (joins are rare, and json keeps every
+//!             field exactly as it was). the room picks the spawn point with its rng
+//!   LEAVE=2:  u16 id
+//!   INPUT=3:  u16 id, input. set outside a tick, like a dropped player being stood still
+//!   NAME=4:   u16 id, u8 name_len + name
+//!   CONFIG=5: config
+//! ```
+
+use super::components::{Arena, Player, PlayerInput};
+use super::world::{SimConfig, TickInputs, World};
+use crate::protocol::Snapshot;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"GAMEREPL";
+const VERSION: u8 = 2;
+
+const EXTENSION: &str = "replay";
+
+const KIND_TICK: u8 = 0;
+const KIND_JOIN: u8 = 1;
+const KIND_LEAVE: u8 = 2;
+const KIND_INPUT: u8 = 3;
+const KIND_NAME: u8 = 4;
+const KIND_CONFIG: u8 = 5;
+
+/// tick records between flushes, about a second at the default tick rate
+const FLUSH_EVERY: u32 = 64;
+
+/// Writes the replay of a room while it plays
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    /// None once writing failed
+    file: Option<BufWriter<File>>,
+    /// tick of the last tick record
+    last_tick: u64,
+    unflushed: u32,
+    buf: Vec<u8>,
+}
+
+impl Recorder {
+    /// start a new file in dir, named after the room and the time
+    pub fn create(
+        dir: &str,
+        room: &str,
+        seed: u64,
+        start_tick: u64,
+        config: &SimConfig,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        // room names come from clients, keep them out of the path
+        let room_part: String = room
+            .chars()
+            .map(
+                |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    true => c,
+                    false => '_',
+                },
+            )
+            .collect();
+        let path = Path::new(dir).join(format!("{room_part}-{ms}.{EXTENSION}"));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let mut recorder = Self {
+            path,
+            file: Some(BufWriter::new(file)),
+            last_tick: start_tick,
+            unflushed: 0,
+            buf: Vec::with_capacity(256),
+        };
+        let buf = &mut recorder.buf;
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&seed.to_le_bytes());
+        buf.extend_from_slice(&start_tick.to_le_bytes());
+        put_name(buf, room);
+        put_config(buf, config);
+        recorder.write();
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn tick(&mut self, tick: u64, inputs: &TickInputs) {
+        let buf = &mut self.buf;
+        buf.push(KIND_TICK);
+        put_u32(buf, tick.saturating_sub(self.last_tick) as u32);
+        self.last_tick = tick;
+        put_u16(buf, inputs.inputs.len() as u16);
+        for input in &inputs.inputs {
+            put_u16(buf, input.id as u16);
+            put_input(buf, input);
+        }
+        put_u16(buf, inputs.view_ticks.len() as u16);
+        for (id, view_tick) in &inputs.view_ticks {
+            put_u16(buf, *id as u16);
+            put_u32(buf, tick.saturating_sub(*view_tick) as u32);
+        }
+        self.write();
+        self.unflushed += 1;
+        if self.unflushed >= FLUSH_EVERY {
+            self.flush();
+        }
+    }
+
+    /// player as it was handed to the room, before it is given a spawn point
+    pub fn join(&mut self, id: usize, name: &str, player: Option<&Player>) {
+        let json = match player.map(serde_json::to_vec).transpose() {
+            Ok(json) => json,
+            Err(e) => return self.fail(&e.to_string()),
+        };
+        let buf = &mut self.buf;
+        buf.push(KIND_JOIN);
+        put_u16(buf, id as u16);
+        put_name(buf, name);
+        match json {
+            Some(json) => {
+                buf.push(1);
+                put_u32(buf, json.len() as u32);
+                buf.extend_from_slice(&json);
+            }
+            None => buf.push(0),
+        }
+        self.write();
+    }
+
+    pub fn leave(&mut self, id: usize) {
+        self.buf.push(KIND_LEAVE);
+        put_u16(&mut self.buf, id as u16);
+        self.write();
+    }
+
+    pub fn input(&mut self, input: &PlayerInput) {
+        self.buf.push(KIND_INPUT);
+        put_u16(&mut self.buf, input.id as u16);
+        put_input(&mut self.buf, input);
+        self.write();
+    }
+
+    pub fn name(&mut self, id: usize, name: &str) {
+        self.buf.push(KIND_NAME);
+        put_u16(&mut self.buf, id as u16);
+        put_name(&mut self.buf, name);
+        self.write();
+    }
+
+    pub fn config(&mut self, config: &SimConfig) {
+        self.buf.push(KIND_CONFIG);
+        put_config(&mut self.buf, config);
+        self.write();
+    }
+
+    pub fn flush(&mut self) {
+        self.unflushed = 0;
+        let result = match &mut self.file {
+            Some(file) => file.flush(),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.fail(&e.to_string());
+        }
+    }
+
+    /// a replay that cant be written stops recording instead of taking the room down with it
+    fn write(&mut self) {
+        let result = match &mut self.file {
+            Some(file) => file.write_all(&self.buf),
+            None => Ok(()),
+        };
+        self.buf.clear();
+        if let Err(e) = result {
+            self.fail(&e.to_string());
+        }
+    }
+
+    fn fail(&mut self, error: &str) {
+        tracing::error!(error, path = %self.path.display(), "failed to write replay, not recording anymore");
+        self.file = None;
+        self.buf.clear();
+    }
+}
+
+/// What happened in a room, in the order it happened
+#[derive(Clone, Debug)]
+pub enum Record {
+    /// simulate tick with these inputs
+    Tick {
+        tick: u64,
+        inputs: TickInputs,
+    },
+    /// player is None for a new one
+    Join {
+        id: usize,
+        name: String,
+        player: Option<Player>,
+    },
+    Leave {
+        id: usize,
+    },
+    /// the input of a player was set between ticks
+    Input(PlayerInput),
+    Name {
+        id: usize,
+        name: String,
+    },
+    Config(SimConfig),
+}
+
+/// A replay file read back
+#[derive(Clone, Debug)]
+pub struct Replay {
+    pub room: String,
+    pub seed: u64,
+    pub config: SimConfig,
+    pub records: Vec<Record>,
+}
+
+impl Replay {
+    /// a replay file name as a recorder makes them, without anything that could leave the directory
+    pub fn is_file_name(name: &str) -> bool {
+        let stem = match name
+            .strip_suffix(EXTENSION)
+            .and_then(|n| n.strip_suffix('.'))
+        {
+            Some(stem) => stem,
+            None => return false,
+        };
+        !stem.is_empty()
+            && stem
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a replay of version {VERSION}", path.display()),
+            )
+        })
+    }
+
+    /// None if the header is not readable. A file cut off in the middle of a record, by a
+    /// crash for example, is read up to that record
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader { bytes };
+        if r.take(MAGIC.len())? != MAGIC || r.u8()? != VERSION {
+            return None;
+        }
+        let seed = r.u64()?;
+        let start_tick = r.u64()?;
+        let room = r.name()?;
+        let config = r.config()?;
+
+        let mut records = Vec::new();
+        let mut tick = start_tick;
+        while !r.bytes.is_empty() {
+            match r.record(&mut tick) {
+                Some(record) => records.push(record),
+                None => break,
+            }
+        }
+        Some(Self {
+            room,
+            seed,
+            config,
+            records,
+        })
+    }
+}
+
+/// Steps a world through a replay, through the same `Player::apply` and `systems::run`
+/// the room ran
+#[derive(Debug)]
+pub struct Playback {
+    replay: Replay,
+    next: usize,
+    world: World,
+    names: HashMap<usize, String>,
+    config: SimConfig,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            world: World::new(replay.seed, replay.config.max_rewind_ticks),
+            config: replay.config,
+            replay,
+            next: 0,
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn room(&self) -> &str {
+        &self.replay.room
+    }
+
+    /// as it is at the current tick, the room can change it
+    pub fn tick_duration(&self) -> Duration {
+        self.config.tick_duration
+    }
+
+    /// play the records up to the next tick and simulate it, None at the end of the replay
+    pub fn next_tick(&mut self) -> Option<Snapshot> {
+        while let Some(record) = self.replay.records.get(self.next) {
+            self.next += 1;
+            match record {
+                Record::Tick { tick, inputs } => {
+                    self.world.step(*tick, inputs, &self.config);
+                    return Some(self.world.snapshot(*tick, &self.names, &HashMap::new()));
+                }
+                Record::Join { id, name, player } => {
+                    self.world.join(*id, *player, &self.config.arena);
+                    self.names.insert(*id, name.clone());
+                }
+                Record::Leave { id } => {
+                    self.world.players.remove(id);
+                    self.names.remove(id);
+                }
+                Record::Input(input) => {
+                    if let Some(player) = self.world.players.get_mut(&input.id) {
+                        player.player_input = *input;
+                    }
+                }
+                Record::Name { id, name } => {
+                    self.names.insert(*id, name.clone());
+                }
+                Record::Config(config) => {
+                    if config.max_rewind_ticks != self.config.max_rewind_ticks {
+                        self.world.set_max_rewind_ticks(config.max_rewind_ticks);
+                    }
+                    self.config = *config;
+                }
+            }
+        }
+        None
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        Some(f32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn config(&mut self) -> Option<SimConfig> {
+        Some(SimConfig {
+            tick_duration: Duration::from_micros(self.u32()? as u64),
+            arena: Arena {
+                spawn_half_extent: self.f32()?,
+                half_extent: self.f32()?,
+            },
+            max_rewind_ticks: self.u32()? as u64,
+        })
+    }
+
+    fn input(&mut self, id: usize) -> Option<PlayerInput> {
+        let seq = self.u32()?;
+        let buttons = self.u8()?;
+        let facing_rad = self.f32()?;
+        Some(PlayerInput {
+            id,
+            seq,
+            step_forward: buttons & 1 != 0,
+            step_backward: buttons & (1 << 1) != 0,
+            step_left: buttons & (1 << 2) != 0,
+            step_right: buttons & (1 << 3) != 0,
+            kick: buttons & (1 << 4) != 0,
+            punch: buttons & (1 << 5) != 0,
+            run: buttons & (1 << 6) != 0,
+            facing_rad,
+        })
+    }
+
+    /// tick is the tick of the last tick record
+    fn record(&mut self, tick: &mut u64) -> Option<Record> {
+        let record = match self.u8()? {
+            KIND_TICK => {
+                let current = *tick + self.u32()? as u64;
+                let mut inputs = TickInputs::default();
+                for _ in 0..self.u16()? {
+                    let id = self.u16()? as usize;
+                    inputs.inputs.push(self.input(id)?);
+                }
+                for _ in 0..self.u16()? {
+                    let id = self.u16()? as usize;
+                    let behind = self.u32()? as u64;
+                    inputs.view_ticks.insert(id, current.saturating_sub(behind));
+                }
+                *tick = current;
+                Record::Tick {
+                    tick: current,
+                    inputs,
+                }
+            }
+            KIND_JOIN => {
+                let id = self.u16()? as usize;
+                let name = self.name()?;
+                let player = match self.u8()? {
+                    0 => None,
+                    _ => {
+                        let len = self.u32()? as usize;
+                        Some(serde_json::from_slice(self.take(len)?).ok()?)
+                    }
+                };
+                Record::Join { id, name, player }
+            }
+            KIND_LEAVE => Record::Leave {
+                id: self.u16()? as usize,
+            },
+            KIND_INPUT => {
+                let id = self.u16()? as usize;
+                Record::Input(self.input(id)?)
+            }
+            KIND_NAME => Record::Name {
+                id: self.u16()? as usize,
+                name: self.name()?,
+            },
+            KIND_CONFIG => Record::Config(self.config()?),
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+/// names are short, anything past 255 bytes is cut at a char boundary
+fn put_name(buf: &mut Vec<u8>, name: &str) {
+    let mut len = name.len().min(u8::MAX as usize);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    buf.push(len as u8);
+    buf.extend_from_slice(&name.as_bytes()[..len]);
+}
+
+fn put_config(buf: &mut Vec<u8>, config: &SimConfig) {
+    put_u32(buf, config.tick_duration.as_micros() as u32);
+    buf.extend_from_slice(&config.arena.spawn_half_extent.to_le_bytes());
+    buf.extend_from_slice(&config.arena.half_extent.to_le_bytes());
+    put_u32(buf, config.max_rewind_ticks as u32);
+}
+
+fn put_input(buf: &mut Vec<u8>, input: &PlayerInput) {
+    put_u32(buf, input.seq);
+    let buttons = [
+        input.step_forward,
+        input.step_backward,
+        input.step_left,
+        input.step_right,
+        input.kick,
+        input.punch,
+        input.run,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0u8, |bits, (i, down)| bits | ((*down as u8) << i));
+    buf.push(buttons);
+    buf.extend_from_slice(&input.facing_rad.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const SEED: u64 = 11;
+
+    fn config() -> SimConfig {
+        SimConfig {
+            tick_duration: Duration::from_millis(17),
+            arena: Arena {
+                spawn_half_extent: 0.5,
+                half_extent: 2.0,
+            },
+            max_rewind_ticks: 10,
+        }
+    }
+
+    /// a room without the clock, so a recording can be scripted tick by tick. the recording of
+    /// the real room is tested in room.rs
+    struct Room {
+        world: World,
+        names: HashMap<usize, String>,
+        recorder: Recorder,
+        config: SimConfig,
+        tick: u64,
+        /// what a client got each tick
+        snapshots: Vec<Snapshot>,
+    }
+
+    impl Room {
+        fn new(dir: &Path) -> Self {
+            let config = config();
+            Self {
+                world: World::new(SEED, config.max_rewind_ticks),
+                names: HashMap::new(),
+                recorder: Recorder::create(dir.to_str().unwrap(), "main", SEED, 0, &config)
+                    .unwrap(),
+                config,
+                tick: 0,
+                snapshots: Vec::new(),
+            }
+        }
+
+        fn join(&mut self, id: usize, player: Option<Player>) {
+            self.recorder
+                .join(id, &format!("player{id}"), player.as_ref());
+            self.world.join(id, player, &self.config.arena);
+            self.names.insert(id, format!("player{id}"));
+        }
+
+        fn leave(&mut self, id: usize) {
+            self.names.remove(&id);
+            self.world.players.remove(&id);
+            self.recorder.leave(id);
+        }
+
+        fn rename(&mut self, id: usize, name: &str) {
+            self.names.insert(id, name.to_owned());
+            self.recorder.name(id, name);
+        }
+
+        /// like a dropped player being stood still
+        fn stand_still(&mut self, id: usize) {
+            let player = self.world.players.get_mut(&id).unwrap();
+            player.player_input = PlayerInput {
+                id,
+                ..PlayerInput::new()
+            };
+            self.recorder.input(&player.player_input);
+        }
+
+        fn set_config(&mut self, config: SimConfig) {
+            if config.max_rewind_ticks != self.config.max_rewind_ticks {
+                self.world.set_max_rewind_ticks(config.max_rewind_ticks);
+            }
+            self.recorder.config(&config);
+            self.config = config;
+        }
+
+        /// one tick, every player looking `behind` ticks into the past
+        fn step(&mut self, inputs: Vec<PlayerInput>, behind: u64) {
+            self.tick += 1;
+            let view_ticks = self
+                .world
+                .players
+                .keys()
+                .map(|id| (*id, self.tick.saturating_sub(behind)))
+                .collect();
+            self.tick_with(TickInputs { inputs, view_ticks });
+        }
+
+        /// ticks with random held inputs, sometimes skipping a tick like a room catching up
+        fn play(&mut self, ticks: u64, rng: &mut StdRng) {
+            for _ in 0..ticks {
+                self.tick += if rng.gen_bool(0.05) { 2 } else { 1 };
+                let mut inputs = TickInputs::default();
+                for id in self.world.players.keys().copied() {
+                    // without new input a player keeps doing what it did
+                    if rng.gen_bool(0.1) {
+                        inputs.inputs.push(PlayerInput {
+                            id,
+                            seq: self.tick as u32,
+                            step_forward: rng.gen_bool(0.4),
+                            step_left: rng.gen_bool(0.3),
+                            kick: rng.gen_bool(0.3),
+                            punch: rng.gen_bool(0.3),
+                            run: rng.gen_bool(0.5),
+                            facing_rad: rng.gen_range(-3.0..3.0),
+                            ..PlayerInput::new()
+                        });
+                    }
+                    let view_tick = self.tick.saturating_sub(rng.gen_range(0..10));
+                    inputs.view_ticks.insert(id, view_tick);
+                }
+                self.tick_with(inputs);
+            }
+        }
+
+        fn tick_with(&mut self, inputs: TickInputs) {
+            self.recorder.tick(self.tick, &inputs);
+            self.world.step(self.tick, &inputs, &self.config);
+            let snapshot = self.world.snapshot(self.tick, &self.names, &HashMap::new());
+            self.snapshots.push(snapshot);
+        }
+    }
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("replay-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// a room with players joining, leaving, renaming, standing still and a config change
+    fn record(dir: &Path) -> (Vec<Snapshot>, PathBuf) {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut room = Room::new(dir);
+        room.join(1, None);
+        room.join(2, None);
+        room.join(4, None);
+        room.play(300, &mut rng);
+
+        let mut from_elsewhere = Player::new([0.0; 3]);
+        from_elsewhere.attributes.health = 40.0;
+        room.join(3, Some(from_elsewhere));
+        room.rename(1, "bob");
+        room.play(300, &mut rng);
+
+        room.stand_still(2);
+        room.set_config(SimConfig {
+            max_rewind_ticks: 4,
+            arena: Arena {
+                spawn_half_extent: 0.5,
+                half_extent: 1.5,
+            },
+            ..room.config
+        });
+        room.play(300, &mut rng);
+
+        room.leave(2);
+        room.play(300, &mut rng);
+        room.recorder.flush();
+        (room.snapshots, room.recorder.path().to_owned())
+    }
+
+    /// 1 kicks where 2 was a moment ago, 2 ran on before the kick landed
+    fn kick_a_running_player(dir: &Path, behind: u64) -> (Vec<Snapshot>, PathBuf) {
+        let mut room = Room::new(dir);
+        // both start in the middle
+        room.set_config(SimConfig {
+            arena: Arena {
+                spawn_half_extent: 0.0,
+                half_extent: 2.0,
+            },
+            ..room.config
+        });
+        room.join(1, None);
+        room.join(2, None);
+        let kick = PlayerInput {
+            id: 1,
+            kick: true,
+            ..PlayerInput::new()
+        };
+        let run = PlayerInput {
+            id: 2,
+            step_backward: true,
+            run: true,
+            ..PlayerInput::new()
+        };
+        room.step(vec![kick, run], behind);
+        for _ in 0..30 {
+            room.step(Vec::new(), behind);
+        }
+        room.recorder.flush();
+        (room.snapshots, room.recorder.path().to_owned())
+    }
+
+    fn json(snapshot: &Snapshot) -> String {
+        serde_json::to_string(snapshot).unwrap()
+    }
+
+    #[test]
+    fn playback_gives_the_snapshots_the_room_sent() {
+        let dir = temp_dir("playback");
+        let (sent, path) = record(&dir);
+        let replay = Replay::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(replay.room, "main");
+        assert_eq!(replay.seed, SEED);
+        assert_eq!(replay.config, config());
+
+        let mut playback = Playback::new(replay);
+        let played: Vec<Snapshot> = std::iter::from_fn(|| playback.next_tick()).collect();
+        assert_eq!(played.len(), sent.len());
+        for (played, sent) in played.iter().zip(&sent) {
+            // json too, PartialEq on f32 would let 0.0 == -0.0 through
+            assert_eq!(played, sent, "tick {}", sent.tick);
+            assert_eq!(json(played), json(sent), "tick {}", sent.tick);
+        }
+        // the run went through everything recorded
+        let last = played.last().unwrap();
+        assert_eq!(
+            last.players.iter().map(|p| p.id).collect::<Vec<_>>(),
+            [1, 3, 4]
+        );
+        assert_eq!(last.players[0].name, "bob");
+        assert!(played
+            .iter()
+            .any(|s| s.players.iter().any(|p| p.is_taking_dmg)));
+    }
+
+    #[test]
+    fn hits_that_only_land_rewound_play_back() {
+        let hit = |snapshots: &[Snapshot]| {
+            snapshots
+                .iter()
+                .any(|s| s.players.iter().any(|p| p.id == 2 && p.is_taking_dmg))
+        };
+        let dir = temp_dir("unrewound");
+        let (now, _) = kick_a_running_player(&dir, 0);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!hit(&now), "2 ran on, the kick should miss");
+
+        let dir = temp_dir("rewound");
+        let (sent, path) = kick_a_running_player(&dir, 3);
+        assert!(hit(&sent), "1 saw 2 before it ran on, the kick should hit");
+
+        let replay = Replay::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut playback = Playback::new(replay);
+        let played: Vec<Snapshot> = std::iter::from_fn(|| playback.next_tick()).collect();
+        assert_eq!(played, sent);
+    }
+
+    #[test]
+    fn records_read_back_as_written() {
+        let dir = temp_dir("records");
+        let (sent, path) = record(&dir);
+        let replay = Replay::load(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let ticks: Vec<u64> = replay
+            .records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Tick { tick, .. } => Some(*tick),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ticks, sent.iter().map(|s| s.tick).collect::<Vec<_>>());
+
+        let others: Vec<&Record> = replay
+            .records
+            .iter()
+            .filter(|r| !matches!(r, Record::Tick { .. }))
+            .collect();
+        assert_eq!(others.len(), 8);
+        match others[3] {
+            Record::Join {
+                id: 3,
+                name,
+                player: Some(player),
+            } => {
+                assert_eq!(name, "player3");
+                assert_eq!(player.attributes.health, 40.0);
+            }
+            other => panic!("expected the join of 3, got {other:?}"),
+        }
+        assert!(matches!(others[4], Record::Name { id: 1, name } if name == "bob"));
+        assert!(matches!(others[5], Record::Input(input) if input.id == 2 && !input.run));
+        assert!(matches!(others[6], Record::Config(c) if c.max_rewind_ticks == 4));
+        assert!(matches!(others[7], Record::Leave { id: 2 }));
+    }
+
+    #[test]
+    fn cut_off_file_is_read_up_to_the_last_whole_record() {
+        let dir = temp_dir("cut");
+        let (_, path) = record(&dir);
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let whole = Replay::from_bytes(&bytes).unwrap().records.len();
+        let cut = Replay::from_bytes(&bytes[..bytes.len() - 3]).unwrap();
+        assert_eq!(cut.records.len(), whole - 1);
+
+        assert!(Replay::from_bytes(&bytes[..20]).is_none());
+        let mut other_version = bytes.clone();
+        other_version[MAGIC.len()] = VERSION + 1;
+        assert!(Replay::from_bytes(&other_version).is_none());
+    }
+}
@@ -0,0 +1,801 @@
+use super::audit::AuditLog;
+use super::clock::{SimClock, MAX_CATCH_UP_TICKS};
+use super::components::{Arena, PlayerInput};
+use super::input::{InputQueue, InputQueueConfig};
+use super::replay::Recorder;
+use super::validation::{InputValidator, Verdict};
+use super::world::{self, SimConfig, TickInputs, World};
+use crate::config::Config;
+use crate::messages;
+use crate::metrics::{self, Metrics};
+use crate::protocol::Snapshot;
+use actix::prelude::*;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+
+/// how many past snapshots are kept as delta baselines (about a second)
+const SNAPSHOT_HISTORY_LEN: usize = 64;
+
+/// ring buffer of the most recent snapshots sent, oldest first
+#[derive(Debug)]
+struct SnapshotHistory {
+    snapshots: VecDeque<Arc<Snapshot>>,
+}
+
+impl SnapshotHistory {
+    fn new() -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(SNAPSHOT_HISTORY_LEN),
+        }
+    }
+
+    fn push(&mut self, snapshot: Arc<Snapshot>) {
+        if self.snapshots.len() == SNAPSHOT_HISTORY_LEN {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// the snapshot of that tick, if it is still in the buffer
+    fn get(&self, tick: u64) -> Option<&Arc<Snapshot>> {
+        // not every tick gets a snapshot when catching up, so search instead of indexing
+        let i = self
+            .snapshots
+            .binary_search_by_key(&tick, |s| s.tick)
+            .ok()?;
+        self.snapshots.get(i)
+    }
+}
+
+/// Settings every room is started with
+#[derive(Clone, Copy, Debug)]
+pub struct RoomConfig {
+    pub tick_duration: Duration,
+    pub arena: Arena,
+    pub input_queue: InputQueueConfig,
+    /// how far back hit detection may rewind victims for a laggy attacker
+    pub max_rewind_ticks: u64,
+    pub max_inputs_per_second: u32,
+    pub max_attack_hold_ticks: u64,
+    /// rooms seed their rng from this and their name, None picks a random seed
+    pub seed: Option<u64>,
+}
+
+impl RoomConfig {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            tick_duration: config.tick_duration(),
+            arena: Arena {
+                spawn_half_extent: config.spawn_half_extent,
+                half_extent: config.arena_half_extent,
+            },
+            input_queue: InputQueueConfig {
+                max_len: config.input_queue_len,
+                overflow: config.input_overflow,
+                catch_up_len: config.input_catch_up_len,
+            },
+            max_rewind_ticks: config.max_rewind_ms / config.tick_ms,
+            max_inputs_per_second: config.max_inputs_per_second,
+            max_attack_hold_ticks: config.max_attack_hold_ms / config.tick_ms,
+            seed: config.seed,
+        }
+    }
+
+    pub fn sim(&self) -> SimConfig {
+        SimConfig {
+            tick_duration: self.tick_duration,
+            arena: self.arena,
+            max_rewind_ticks: self.max_rewind_ticks,
+        }
+    }
+}
+
+/// A single match: its own players, simulated and snapshotted on its own tick independently of other rooms
+#[derive(Debug)]
+pub struct Room {
+    name: String,
+    world: World,
+    /// display names, they go in the snapshots
+    names: HashMap<usize, String>,
+    sessions: HashMap<usize, Recipient<messages::SnapshotMessage>>,
+    clock: SimClock,
+    /// when the last tick was simulated, for spotting a stuck tick loop
+    last_tick_at: Instant,
+    history: SnapshotHistory,
+    /// last snapshot tick each client said it received
+    acks: HashMap<usize, u64>,
+    /// the tick each session was added or attached at, it has no snapshot of that tick or older
+    attached_at: HashMap<usize, u64>,
+    inputs: HashMap<usize, InputQueue>,
+    validators: HashMap<usize, InputValidator>,
+    /// round trip time of each client in ms
+    pings: HashMap<usize, u16>,
+    config: RoomConfig,
+    /// None if replays are not recorded
+    recorder: Option<Recorder>,
+    audit: AuditLog,
+    metrics: Arc<Metrics>,
+    /// parent of everything the room logs
+    span: tracing::Span,
+}
+
+impl Room {
+    /// with replay_dir set the room records a replay into it
+    pub fn new(
+        name: &str,
+        config: RoomConfig,
+        audit: AuditLog,
+        metrics: Arc<Metrics>,
+        replay_dir: Option<&str>,
+    ) -> Self {
+        let span = tracing::info_span!("room", room = %name);
+        let seed = match config.seed {
+            Some(seed) => world::room_seed(seed, name),
+            None => rand::thread_rng().gen(),
+        };
+        let recorder =
+            replay_dir.and_then(
+                |dir| match Recorder::create(dir, name, seed, 0, &config.sim()) {
+                    Ok(recorder) => {
+                        let path = recorder.path().display();
+                        tracing::info!(parent: &span, %path, "recording replay");
+                        Some(recorder)
+                    }
+                    Err(e) => {
+                        tracing::error!(parent: &span, error = %e, dir, "cant record replay");
+                        None
+                    }
+                },
+            );
+        Self {
+            name: name.to_owned(),
+            world: World::new(seed, config.max_rewind_ticks),
+            names: HashMap::with_capacity(10),
+            sessions: HashMap::with_capacity(10),
+            clock: SimClock::new(config.tick_duration),
+            last_tick_at: Instant::now(),
+            history: SnapshotHistory::new(),
+            acks: HashMap::with_capacity(10),
+            attached_at: HashMap::with_capacity(10),
+            inputs: HashMap::with_capacity(10),
+            validators: HashMap::with_capacity(10),
+            pings: HashMap::with_capacity(10),
+            config,
+            recorder,
+            audit,
+            metrics,
+            span,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.world
+            .snapshot(self.clock.tick(), &self.names, &self.pings)
+    }
+
+    /// simulate one tick
+    fn step(&mut self) {
+        self.clock.advance();
+        if self.world.players.is_empty() {
+            return;
+        }
+        let tick = self.clock.tick();
+        let mut inputs = TickInputs {
+            inputs: Vec::with_capacity(self.world.players.len()),
+            view_ticks: self.view_ticks(),
+        };
+        for id in self.world.players.keys() {
+            // without new input the player keeps doing what it did last tick
+            if let Some(mut input) = self.inputs.get_mut(id).and_then(|q| q.pop()) {
+                input.id = *id;
+                inputs.inputs.push(input);
+            }
+        }
+        for (id, player) in &self.world.players {
+            let input = inputs
+                .inputs
+                .iter()
+                .find(|input| input.id == *id)
+                .unwrap_or(&player.player_input);
+            if let Some(validator) = self.validators.get_mut(id) {
+                if let Verdict::Flag(what) = validator.check_attack(input, tick) {
+                    self.audit.record(&self.name, *id, &what);
+                }
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.tick(tick, &inputs);
+        }
+        self.world.step(tick, &inputs, &self.config.sim());
+    }
+
+    fn send_snapshot(&mut self) {
+        if self.world.players.is_empty() {
+            return;
+        }
+        let snapshot = Arc::new(self.snapshot());
+        for (id, addr) in &self.sessions {
+            // no baseline if the client never acked or its ack is too old: full keyframe
+            let baseline = self
+                .acks
+                .get(id)
+                .and_then(|tick| self.history.get(*tick))
+                .cloned();
+            addr.do_send(messages::SnapshotMessage {
+                snapshot: snapshot.clone(),
+                baseline,
+            });
+        }
+        self.history.push(snapshot);
+    }
+
+    fn new_validator(&self) -> InputValidator {
+        let tick_ms = (self.clock.tick_duration().as_millis() as u64).max(1);
+        InputValidator::new(
+            self.config.max_inputs_per_second,
+            1000 / tick_ms,
+            self.config.max_attack_hold_ticks,
+        )
+    }
+
+    /// each client last acked the snapshot it was looking at, that is where its attacks are aimed.
+    /// clients that dont ack are assumed to be a round trip behind
+    fn view_ticks(&self) -> HashMap<usize, u64> {
+        let tick = self.clock.tick();
+        let tick_ms = (self.clock.tick_duration().as_millis() as u64).max(1);
+        let oldest = tick.saturating_sub(self.config.max_rewind_ticks);
+        self.world
+            .players
+            .keys()
+            .filter_map(|id| {
+                let view_tick = match (self.acks.get(id), self.pings.get(id)) {
+                    (Some(ack), _) => *ack,
+                    (None, Some(rtt_ms)) => tick.saturating_sub(*rtt_ms as u64 / tick_ms),
+                    (None, None) => return None,
+                };
+                Some((*id, view_tick.max(oldest)))
+            })
+            .collect()
+    }
+
+    /// run every tick that is due, send one snapshot of the result and sleep until the next tick
+    fn run_due_ticks(&mut self, ctx: &mut Context<Self>) {
+        let mut due = self.clock.due(Instant::now());
+        if due > MAX_CATCH_UP_TICKS {
+            tracing::warn!(
+                parent: &self.span,
+                behind = due,
+                skipped = due - MAX_CATCH_UP_TICKS,
+                "room fell behind, skipping ticks"
+            );
+            self.clock.skip(due - MAX_CATCH_UP_TICKS);
+            self.metrics
+                .ticks_skipped
+                .fetch_add(due - MAX_CATCH_UP_TICKS, Ordering::Relaxed);
+            due = MAX_CATCH_UP_TICKS;
+        }
+
+        for _ in 0..due {
+            let started = Instant::now();
+            self.step();
+            let took = started.elapsed();
+            self.last_tick_at = started + took;
+            self.metrics.tick_seconds.observe(took.as_secs_f64());
+            if took > self.clock.tick_duration() {
+                metrics::inc(&self.metrics.tick_overruns);
+                tracing::warn!(
+                    parent: &self.span,
+                    tick = self.clock.tick(),
+                    ?took,
+                    budget = ?self.clock.tick_duration(),
+                    "tick overran its budget"
+                );
+            }
+        }
+        if due > 0 {
+            self.send_snapshot();
+        }
+
+        let until_next = self.clock.until_next(Instant::now());
+        ctx.run_later(until_next, |act, ctx| act.run_due_ticks(ctx));
+    }
+}
+
+impl Actor for Room {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.run_due_ticks(ctx)
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush();
+        }
+    }
+}
+
+impl Handler<messages::AddPlayerMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::AddPlayerMessage, _: &mut Context<Self>) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.join(msg.id, &msg.name, msg.player.as_ref());
+        }
+        self.sessions.insert(msg.id, msg.addr);
+        // a late ack from the room the player came from could match a tick of this one
+        self.acks.remove(&msg.id);
+        self.attached_at.insert(msg.id, self.clock.tick());
+        self.world.join(msg.id, msg.player, &self.config.arena);
+        self.names.insert(msg.id, msg.name);
+        self.inputs
+            .insert(msg.id, InputQueue::new(self.config.input_queue));
+        self.validators.insert(msg.id, self.new_validator());
+    }
+}
+
+impl Handler<messages::DetachSessionMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::DetachSessionMessage, _: &mut Context<Self>) {
+        self.sessions.remove(&msg.id);
+        self.acks.remove(&msg.id);
+        self.attached_at.remove(&msg.id);
+        self.inputs.remove(&msg.id);
+        self.validators.remove(&msg.id);
+        self.pings.remove(&msg.id);
+        // stand still instead of running off the edge while nobody is controlling it
+        if let Some(player) = self.world.players.get_mut(&msg.id) {
+            player.player_input = PlayerInput {
+                id: msg.id,
+                seq: player.player_input.seq,
+                facing_rad: player.player_input.facing_rad,
+                ..PlayerInput::new()
+            };
+            if let Some(recorder) = &mut self.recorder {
+                recorder.input(&player.player_input);
+            }
+        }
+    }
+}
+
+impl Handler<messages::AttachSessionMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::AttachSessionMessage, _: &mut Context<Self>) {
+        if !self.world.players.contains_key(&msg.id) {
+            return;
+        }
+        // the old connection may not have been detached, what it acked the new client never got
+        self.acks.remove(&msg.id);
+        self.pings.remove(&msg.id);
+        self.attached_at.insert(msg.id, self.clock.tick());
+        self.sessions.insert(msg.id, msg.addr);
+        self.inputs
+            .insert(msg.id, InputQueue::new(self.config.input_queue));
+        self.validators.insert(msg.id, self.new_validator());
+    }
+}
+
+impl Handler<messages::RemovePlayerMessage> for Room {
+    type Result = MessageResult<messages::RemovePlayerMessage>;
+
+    fn handle(
+        &mut self,
+        msg: messages::RemovePlayerMessage,
+        _: &mut Context<Self>,
+    ) -> Self::Result {
+        self.sessions.remove(&msg.id);
+        self.acks.remove(&msg.id);
+        self.attached_at.remove(&msg.id);
+        self.inputs.remove(&msg.id);
+        self.validators.remove(&msg.id);
+        self.pings.remove(&msg.id);
+        self.names.remove(&msg.id);
+        let player = self.world.players.remove(&msg.id);
+        if let (Some(recorder), Some(_)) = (&mut self.recorder, &player) {
+            recorder.leave(msg.id);
+        }
+        MessageResult(player)
+    }
+}
+
+impl Handler<messages::PlayerNameMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::PlayerNameMessage, _: &mut Context<Self>) {
+        if let Some(name) = self.names.get_mut(&msg.id) {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.name(msg.id, &msg.name);
+            }
+            *name = msg.name;
+        }
+    }
+}
+
+impl Handler<messages::PlayerInput> for Room {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: messages::PlayerInput, _: &mut Context<Self>) {
+        let (queue, validator) = match (
+            self.inputs.get_mut(&msg.id),
+            self.validators.get_mut(&msg.id),
+        ) {
+            (Some(queue), Some(validator)) => (queue, validator),
+            _ => return,
+        };
+        match validator.check(&mut msg, self.clock.tick()) {
+            Verdict::Accept => (),
+            Verdict::Flag(what) => self.audit.record(&self.name, msg.id, &what),
+            Verdict::Reject(what) => {
+                self.audit
+                    .record(&self.name, msg.id, &format!("input rejected: {what}"));
+                return;
+            }
+            Verdict::Drop => return,
+        }
+        queue.push(msg);
+    }
+}
+
+impl Handler<messages::SnapshotAckMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::SnapshotAckMessage, _: &mut Context<Self>) {
+        // acks can arrive out of order, and a client can not have seen the future
+        if msg.tick > self.clock.tick() || self.sessions.get(&msg.id) != Some(&msg.addr) {
+            return;
+        }
+        // or anything from before it was in this room
+        match self.attached_at.get(&msg.id) {
+            Some(attached) if msg.tick > *attached => (),
+            _ => return,
+        }
+        let ack = self.acks.entry(msg.id).or_insert(msg.tick);
+        *ack = (*ack).max(msg.tick);
+    }
+}
+
+impl Handler<messages::PlayerPingMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::PlayerPingMessage, _: &mut Context<Self>) {
+        if self.sessions.contains_key(&msg.id) {
+            self.pings.insert(msg.id, msg.rtt_ms);
+        }
+    }
+}
+
+impl Handler<messages::CloseRoomMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, _: messages::CloseRoomMessage, ctx: &mut Context<Self>) {
+        tracing::info!(parent: &self.span, "closing room");
+        ctx.stop();
+    }
+}
+
+impl Handler<messages::FlushRoomMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, _: messages::FlushRoomMessage, _: &mut Context<Self>) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.flush();
+        }
+    }
+}
+
+impl Handler<messages::RoomInfoMessage> for Room {
+    type Result = MessageResult<messages::RoomInfoMessage>;
+
+    fn handle(&mut self, _: messages::RoomInfoMessage, _: &mut Context<Self>) -> Self::Result {
+        let players = self
+            .world
+            .players
+            .iter()
+            .map(|(id, p)| messages::PlayerInfo {
+                id: *id,
+                name: self.names.get(id).cloned().unwrap_or_default(),
+                pos: p.transform.pos,
+                health: p.attributes.health,
+                ping_ms: self.pings.get(id).copied().unwrap_or(0),
+                connected: self.sessions.contains_key(id),
+            })
+            .collect();
+        MessageResult(messages::RoomInfo {
+            name: self.name.clone(),
+            tick: self.clock.tick(),
+            players,
+        })
+    }
+}
+
+impl Handler<messages::RoomConfigMessage> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::RoomConfigMessage, _: &mut Context<Self>) {
+        let config = msg.config;
+        if config.tick_duration != self.clock.tick_duration() {
+            self.clock
+                .set_tick_duration(config.tick_duration, Instant::now());
+        }
+        if config.max_rewind_ticks != self.config.max_rewind_ticks {
+            self.world.set_max_rewind_ticks(config.max_rewind_ticks);
+        }
+        if let (Some(recorder), true) = (&mut self.recorder, config.sim() != self.config.sim()) {
+            recorder.config(&config.sim());
+        }
+        self.config = config;
+        let ids: Vec<usize> = self.validators.keys().copied().collect();
+        for id in ids {
+            let validator = self.new_validator();
+            self.validators.insert(id, validator);
+        }
+    }
+}
+
+impl Handler<messages::RoomStatsMessage> for Room {
+    type Result = MessageResult<messages::RoomStatsMessage>;
+
+    fn handle(&mut self, msg: messages::RoomStatsMessage, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(metrics::RoomStats {
+            name: self.name.clone(),
+            sessions: self.sessions.len(),
+            players: self.world.players.len(),
+            mailbox_wait: msg.sent.elapsed(),
+            tick_age: self.last_tick_at.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::replay::{Playback, Replay};
+    use super::*;
+
+    const TICK: Duration = Duration::from_millis(5);
+
+    /// stands in for a session, keeps every snapshot the room sends it
+    #[derive(Default)]
+    struct Client {
+        got: Vec<Got>,
+    }
+
+    /// a snapshot and the tick of the baseline it came with
+    #[derive(Clone)]
+    struct Got(Arc<Snapshot>, Option<u64>);
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<messages::SnapshotMessage> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: messages::SnapshotMessage, _: &mut Context<Self>) {
+            self.got
+                .push(Got(msg.snapshot, msg.baseline.map(|b| b.tick)));
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<Got>")]
+    struct Received;
+
+    impl Handler<Received> for Client {
+        type Result = MessageResult<Received>;
+
+        fn handle(&mut self, _: Received, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.got.clone())
+        }
+    }
+
+    fn start_room(replay_dir: Option<&str>) -> Addr<Room> {
+        let config = Config {
+            tick_ms: TICK.as_millis() as u64,
+            ..Config::default()
+        };
+        let audit = AuditLog::open(None).unwrap();
+        let metrics = Arc::new(Metrics::new(config.tick_duration()));
+        Room::new("main", RoomConfig::new(&config), audit, metrics, replay_dir).start()
+    }
+
+    /// what the client got once it satisfies done, panics if that takes too long
+    async fn wait_until(client: &Addr<Client>, done: impl Fn(&[Got]) -> bool) -> Vec<Got> {
+        for _ in 0..1000 {
+            let got = client.send(Received).await.unwrap();
+            if done(&got) {
+                return got;
+            }
+            actix::clock::sleep(TICK).await;
+        }
+        panic!("the client never got what it was waiting for");
+    }
+
+    fn ack(id: usize, tick: u64, client: &Addr<Client>) -> messages::SnapshotAckMessage {
+        messages::SnapshotAckMessage {
+            id,
+            tick,
+            addr: client.clone().recipient(),
+        }
+    }
+
+    fn snapshot(tick: u64) -> Arc<Snapshot> {
+        Arc::new(Snapshot {
+            tick,
+            players: vec![],
+        })
+    }
+
+    #[test]
+    fn acks_older_than_the_history_get_a_keyframe() {
+        let mut history = SnapshotHistory::new();
+        // a tick is skipped now and then when catching up
+        let ticks: Vec<u64> = (1..=2 * SNAPSHOT_HISTORY_LEN as u64)
+            .filter(|tick| tick % 7 != 0)
+            .collect();
+        for tick in &ticks {
+            history.push(snapshot(*tick));
+        }
+        let kept = &ticks[ticks.len() - SNAPSHOT_HISTORY_LEN..];
+        for tick in kept {
+            assert_eq!(history.get(*tick).map(|s| s.tick), Some(*tick));
+        }
+        // fell out of the ring, never snapshotted, or not there yet
+        assert!(history.get(ticks[0]).is_none());
+        assert!(history.get(kept[0] - 1).is_none());
+        assert!(history.get(70).is_none());
+        assert!(history.get(ticks[ticks.len() - 1] + 1).is_none());
+    }
+
+    #[actix::test]
+    async fn resuming_before_the_old_connection_timed_out_starts_from_a_keyframe() {
+        let room = start_room(None);
+        let old = Client::default().start();
+        room.send(add_player(1, &old)).await.unwrap();
+        let got = wait_until(&old, |got| !got.is_empty()).await;
+        let acked = got[0].0.tick;
+        room.send(ack(1, acked, &old)).await.unwrap();
+        wait_until(&old, |got| got.last().unwrap().1 == Some(acked)).await;
+
+        // the old connection is dead but was never detached
+        let new = Client::default().start();
+        room.send(messages::AttachSessionMessage {
+            id: 1,
+            addr: new.clone().recipient(),
+        })
+        .await
+        .unwrap();
+        // and an ack of it arrives late
+        let got = old.send(Received).await.unwrap();
+        room.send(ack(1, got.last().unwrap().0.tick, &old))
+            .await
+            .unwrap();
+
+        let got = wait_until(&new, |got| got.len() >= 3).await;
+        for Got(snapshot, baseline) in &got {
+            assert_eq!(*baseline, None, "tick {}", snapshot.tick);
+        }
+        // what the new client acks counts
+        let acked = got[0].0.tick;
+        room.send(ack(1, acked, &new)).await.unwrap();
+        wait_until(&new, |got| got.last().unwrap().1 == Some(acked)).await;
+    }
+
+    fn add_player(id: usize, client: &Addr<Client>) -> messages::AddPlayerMessage {
+        messages::AddPlayerMessage {
+            id,
+            name: format!("player{id}"),
+            addr: client.clone().recipient(),
+            player: None,
+        }
+    }
+
+    #[actix::test]
+    async fn acks_from_before_the_join_are_ignored() {
+        let room = start_room(None);
+        let other = Client::default().start();
+        room.send(add_player(2, &other)).await.unwrap();
+        let got = wait_until(&other, |got| got.len() >= 2).await;
+        let before = got[0].0.tick;
+
+        // moved here from another room, where it acked a tick this room had too
+        let client = Client::default().start();
+        room.send(add_player(1, &client)).await.unwrap();
+        room.send(ack(1, before, &client)).await.unwrap();
+        let got = wait_until(&client, |got| got.len() >= 3).await;
+        for Got(snapshot, baseline) in &got {
+            assert_eq!(*baseline, None, "tick {}", snapshot.tick);
+        }
+        let acked = got[0].0.tick;
+        room.send(ack(1, acked, &client)).await.unwrap();
+        wait_until(&client, |got| got.last().unwrap().1 == Some(acked)).await;
+    }
+
+    fn input(id: usize, seq: u32) -> PlayerInput {
+        PlayerInput {
+            id,
+            seq,
+            ..PlayerInput::new()
+        }
+    }
+
+    #[actix::test]
+    async fn the_replay_plays_back_what_the_clients_got() {
+        let dir = std::env::temp_dir().join(format!("replay-room-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let room = start_room(dir.to_str());
+        let (one, two) = (Client::default().start(), Client::default().start());
+        let got_more = |client: &Addr<Client>, n: usize| {
+            let client = client.clone();
+            async move {
+                let had = client.send(Received).await.unwrap().len();
+                wait_until(&client, |got| got.len() >= had + n).await
+            }
+        };
+        room.send(add_player(1, &one)).await.unwrap();
+        room.send(add_player(2, &two)).await.unwrap();
+        for (id, seq) in [(1, 1), (2, 1)] {
+            room.do_send(PlayerInput {
+                step_forward: true,
+                kick: id == 1,
+                facing_rad: 1.0,
+                ..input(id, seq)
+            });
+        }
+        got_more(&one, 5).await;
+
+        room.send(messages::PlayerNameMessage {
+            id: 1,
+            name: String::from("bob"),
+        })
+        .await
+        .unwrap();
+        room.send(PlayerInput {
+            step_left: true,
+            run: true,
+            ..input(2, 2)
+        })
+        .await
+        .unwrap();
+        got_more(&one, 5).await;
+
+        // 2 loses its connection and stands still, then the settings change
+        room.send(messages::DetachSessionMessage { id: 2 })
+            .await
+            .unwrap();
+        let mut config = RoomConfig::new(&Config::default());
+        config.tick_duration = TICK;
+        config.arena.half_extent = 3.0;
+        config.max_rewind_ticks = 2;
+        room.send(messages::RoomConfigMessage { config })
+            .await
+            .unwrap();
+        got_more(&one, 5).await;
+
+        room.send(messages::RemovePlayerMessage { id: 2 })
+            .await
+            .unwrap();
+        got_more(&one, 5).await;
+        room.send(messages::FlushRoomMessage).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let replay = Replay::load(&files[0].as_ref().unwrap().path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut playback = Playback::new(replay);
+        let played: HashMap<u64, Snapshot> = std::iter::from_fn(|| playback.next_tick())
+            .map(|snapshot| (snapshot.tick, snapshot))
+            .collect();
+
+        let got = one.send(Received).await.unwrap();
+        let last = &got.last().unwrap().0;
+        assert_eq!(last.players.len(), 1);
+        assert_eq!(last.players[0].name, "bob");
+        for Got(snapshot, _) in got.iter().chain(&two.send(Received).await.unwrap()) {
+            assert_eq!(played.get(&snapshot.tick), Some(&**snapshot));
+        }
+    }
+}
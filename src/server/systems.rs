@@ -1,88 +1,75 @@
 use super::components::{Renderable, Transform};
-use crate::server::components::{AnimTargetId, Player, Projectile, Vao};
+use super::history::Rewind;
+use crate::server::components::{AnimTargetId, Arena, Player, Projectile, Vao};
 use gl_matrix::vec3;
-use rand::{self, Rng};
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::BTreeMap;
 
-pub fn run(players: &mut HashMap<usize, Player>) {
-    gravity(players);
+/// rng is the only randomness a tick may use, so it can be replayed
+pub fn run<R: Rng>(
+    players: &mut BTreeMap<usize, Player>,
+    rewind: &Rewind,
+    arena: &Arena,
+    rng: &mut R,
+) {
+    gravity(players, arena, rng);
     spawn_attack_projectiles(players);
-    recievedmg(players);
+    recievedmg(players, rewind, arena, rng);
 }
 
-fn gravity(players: &mut HashMap<usize, Player>) {
-    for (_id, player) in players {
+fn gravity<R: Rng>(players: &mut BTreeMap<usize, Player>, arena: &Arena, rng: &mut R) {
+    for player in players.values_mut() {
         let p = player.transform.pos;
-        if p[1] < 0.0 || p[0] < -10.0 || p[0] > 10.0 || p[2] < -10.0 || p[2] > 10.0 {
+        if p[1] < 0.0 || !arena.contains(&p) {
             player.transform.pos[1] -= 0.1;
         }
 
         if player.transform.pos[1] < -8.0 {
-            player.respawn();
+            player.respawn(arena, rng);
         }
     }
 }
 
-fn spawn_attack_projectiles(players: &mut HashMap<usize, Player>) {
-    for (_id, player) in players {
+fn spawn_attack_projectiles(players: &mut BTreeMap<usize, Player>) {
+    for player in players.values_mut() {
         match player.anim_target_id {
-            AnimTargetId::Kick => {
-                if player.anim_ticks == 20 {
-                    let mut offset = vec3::create();
-                    vec3::transform_quat(
-                        &mut offset,
-                        &vec3::from_values(0.0, 0.7, 1.2),
-                        &player.transform.quat,
-                    );
-                    let transform = Transform {
-                        pos: vec3::add(&mut vec3::create(), &player.transform.pos, &offset),
-                        quat: player.transform.quat,
-                    };
-                    player.projectile = Some(Projectile {
-                        ticks: 0,
-                        ticks_lifetime: 1,
-                        transform,
-                        renderable: Renderable::new(Vao::Unitcube),
-                    })
-                }
-            }
-            AnimTargetId::Punch => {
-                if player.anim_ticks == 20 {
-                    let mut offset = vec3::create();
-                    vec3::transform_quat(
-                        &mut offset,
-                        &vec3::from_values(0.0, 0.7, 1.2),
-                        &player.transform.quat,
-                    );
-                    let transform = Transform {
-                        pos: vec3::add(&mut vec3::create(), &player.transform.pos, &offset),
-                        quat: player.transform.quat,
-                    };
-                    player.projectile = Some(Projectile {
-                        ticks: 0,
-                        ticks_lifetime: 1,
-                        transform,
-                        renderable: Renderable::new(Vao::Unitcube),
-                    })
-                }
+            AnimTargetId::Kick | AnimTargetId::Punch if player.anim_ticks == 20 => {
+                let mut offset = vec3::create();
+                vec3::transform_quat(
+                    &mut offset,
+                    &vec3::from_values(0.0, 0.7, 1.2),
+                    &player.transform.quat,
+                );
+                let transform = Transform {
+                    pos: vec3::add(&mut vec3::create(), &player.transform.pos, &offset),
+                    quat: player.transform.quat,
+                };
+                player.projectile = Some(Projectile {
+                    ticks: 0,
+                    ticks_lifetime: 1,
+                    transform,
+                    renderable: Renderable::new(Vao::Unitcube),
+                })
             }
             _ => (),
         }
 
-        match &mut player.projectile {
-            Some(proj) => proj.renderable.apply(&proj.transform),
-            _ => (),
+        if let Some(proj) = &mut player.projectile {
+            proj.renderable.apply(&proj.transform)
         }
     }
 }
 
-fn recievedmg(players: &mut HashMap<usize, Player>) {
+/// hits are checked against where the attacker saw the victim (lag compensation), not where it is now
+fn recievedmg<R: Rng>(
+    players: &mut BTreeMap<usize, Player>,
+    rewind: &Rewind,
+    arena: &Arena,
+    rng: &mut R,
+) {
     let projectiles: Vec<(usize, Projectile)> = players
         .iter()
-        .filter_map(|(id, player)| match player.projectile {
-            Some(proj) => Some((*id, proj)),
-            None => None,
-        })
+        .filter_map(|(id, player)| player.projectile.map(|proj| (*id, proj)))
         .collect();
 
     for (id, player) in players {
@@ -92,12 +79,15 @@ fn recievedmg(players: &mut HashMap<usize, Player>) {
                 continue;
             }
 
-            if vec3::dist(&projectile.transform.pos, &player.transform.pos) < 1.0 {
+            let victim = rewind
+                .victim_transform(*attacker_id, *id)
+                .unwrap_or(&player.transform);
+            if vec3::dist(&projectile.transform.pos, &victim.pos) < 1.0 {
                 player.attributes.health -= 10.0;
                 player.attributes.is_taking_dmg = true;
             }
             if player.attributes.health <= 0.0 {
-                player.respawn();
+                player.respawn(arena, rng);
             }
         }
     }
@@ -0,0 +1,140 @@
+use super::components::PlayerInput;
+use std::f32::consts::{PI, TAU};
+
+/// What to do with an input that arrived
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    /// accept it, but it looks like a bot or a modified client
+    Flag(String),
+    Reject(String),
+    /// reject it without writing it down again, it was already reported
+    Drop,
+}
+
+/// Checks the input of one player before it is queued: no values a real client cant send,
+/// and no more input than a real client sends. Attacks are checked every tick instead, a held
+/// attack repeats without the client sending anything
+#[derive(Debug)]
+pub struct InputValidator {
+    max_per_second: u32,
+    ticks_per_second: u64,
+    max_attack_hold_ticks: u64,
+    /// tick the current one second window started at, and inputs in it so far
+    window_start: u64,
+    in_window: u32,
+    /// tick the attack held right now was first seen at
+    attack_since: Option<u64>,
+    attack_flagged: bool,
+}
+
+impl InputValidator {
+    pub fn new(max_per_second: u32, ticks_per_second: u64, max_attack_hold_ticks: u64) -> Self {
+        Self {
+            max_per_second,
+            ticks_per_second: ticks_per_second.max(1),
+            max_attack_hold_ticks,
+            window_start: 0,
+            in_window: 0,
+            attack_since: None,
+            attack_flagged: false,
+        }
+    }
+
+    /// check input arriving at tick, fixing what can be fixed in place
+    pub fn check(&mut self, input: &mut PlayerInput, tick: u64) -> Verdict {
+        if !input.facing_rad.is_finite() {
+            return Verdict::Reject(format!("non-finite facing_rad {}", input.facing_rad));
+        }
+        // any angle is fine, but keep it in one turn so it cant grow without bound
+        input.facing_rad = (input.facing_rad + PI).rem_euclid(TAU) - PI;
+
+        if tick >= self.window_start + self.ticks_per_second {
+            self.window_start = tick;
+            self.in_window = 0;
+        }
+        self.in_window += 1;
+        if self.in_window > self.max_per_second {
+            // report the first one of every window, the rest would just repeat it
+            return match self.in_window - self.max_per_second {
+                1 => Verdict::Reject(format!(
+                    "more than {} inputs per second",
+                    self.max_per_second
+                )),
+                _ => Verdict::Drop,
+            };
+        }
+        Verdict::Accept
+    }
+
+    /// check the input the player is simulated with at tick, new or repeated
+    pub fn check_attack(&mut self, input: &PlayerInput, tick: u64) -> Verdict {
+        if input.kick || input.punch {
+            let since = *self.attack_since.get_or_insert(tick);
+            let held = tick - since;
+            if held > self.max_attack_hold_ticks && !self.attack_flagged {
+                self.attack_flagged = true;
+                return Verdict::Flag(format!("attack held for {held} ticks"));
+            }
+        } else {
+            self.attack_since = None;
+            self.attack_flagged = false;
+        }
+        Verdict::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kick() -> PlayerInput {
+        PlayerInput {
+            kick: true,
+            ..PlayerInput::new()
+        }
+    }
+
+    #[test]
+    fn an_attack_sent_once_and_held_is_flagged() {
+        let mut validator = InputValidator::new(120, 60, 10);
+        assert_eq!(validator.check(&mut kick(), 1), Verdict::Accept);
+        // nothing new arrives, the room repeats the kick every tick
+        for tick in 1..=11 {
+            assert_eq!(validator.check_attack(&kick(), tick), Verdict::Accept);
+        }
+        assert_eq!(
+            validator.check_attack(&kick(), 12),
+            Verdict::Flag(String::from("attack held for 11 ticks"))
+        );
+        // once per hold
+        assert_eq!(validator.check_attack(&kick(), 13), Verdict::Accept);
+
+        assert_eq!(
+            validator.check_attack(&PlayerInput::new(), 14),
+            Verdict::Accept
+        );
+        for tick in 15..=25 {
+            assert_eq!(validator.check_attack(&kick(), tick), Verdict::Accept);
+        }
+        assert!(matches!(
+            validator.check_attack(&kick(), 26),
+            Verdict::Flag(_)
+        ));
+    }
+
+    #[test]
+    fn more_inputs_than_allowed_are_rejected_once_then_dropped() {
+        let mut validator = InputValidator::new(3, 60, 10);
+        for _ in 0..3 {
+            assert_eq!(validator.check(&mut kick(), 5), Verdict::Accept);
+        }
+        assert!(matches!(
+            validator.check(&mut kick(), 5),
+            Verdict::Reject(_)
+        ));
+        assert_eq!(validator.check(&mut kick(), 6), Verdict::Drop);
+        // a second later the window starts over
+        assert_eq!(validator.check(&mut kick(), 65), Verdict::Accept);
+    }
+}
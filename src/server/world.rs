@@ -0,0 +1,200 @@
+use super::components::{Arena, Player, PlayerInput};
+use super::history::{Rewind, TransformHistory};
+use super::systems;
+use crate::protocol::{PlayerState, ProjectileState, Snapshot};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// The part of the room config a tick depends on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimConfig {
+    pub tick_duration: Duration,
+    pub arena: Arena,
+    pub max_rewind_ticks: u64,
+}
+
+/// What one tick depends on from outside the world. Rooms build it from their input
+/// queues and acks, replays read it back from a recording
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TickInputs {
+    /// taken off the input queues this tick, players without one keep doing what they did
+    pub inputs: Vec<PlayerInput>,
+    /// the tick each attacker was looking at, for lag compensation
+    pub view_ticks: HashMap<usize, u64>,
+}
+
+/// The simulated part of a room: the players, where they were the last ticks and the rng
+/// that decides where they spawn. Nothing else goes into a tick, so stepping a world
+/// from the same seed with the same inputs again gives the same players, bit for bit
+#[derive(Debug)]
+pub struct World {
+    /// ordered by id, which player is hit or respawns first must not depend on a hash
+    pub players: BTreeMap<usize, Player>,
+    transforms: TransformHistory,
+    rng: StdRng,
+}
+
+impl World {
+    pub fn new(seed: u64, max_rewind_ticks: u64) -> Self {
+        Self {
+            players: BTreeMap::new(),
+            transforms: TransformHistory::new(max_rewind_ticks),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// put a player at a random spawn point, a new one if player is None. a player coming
+    /// from another room keeps its entity (health etc) but gets a fresh spot
+    pub fn join(&mut self, id: usize, player: Option<Player>, arena: &Arena) {
+        let pos = arena.spawn_pos(&mut self.rng);
+        let mut player = player.unwrap_or_else(|| Player::new(pos));
+        player.transform.pos = pos;
+        self.players.insert(id, player);
+    }
+
+    /// simulate tick
+    pub fn step(&mut self, tick: u64, inputs: &TickInputs, config: &SimConfig) {
+        let ticks_per_second = 1.0 / config.tick_duration.as_secs_f32();
+        for input in &inputs.inputs {
+            if let Some(player) = self.players.get_mut(&input.id) {
+                player.player_input = *input;
+            }
+        }
+        for player in self.players.values_mut() {
+            player.apply(ticks_per_second);
+        }
+        let rewind = Rewind::new(&self.transforms, &inputs.view_ticks);
+        systems::run(&mut self.players, &rewind, &config.arena, &mut self.rng);
+        self.transforms.push(tick, &self.players);
+    }
+
+    /// forgets where players were, hits cant be rewound until the history fills up again
+    pub fn set_max_rewind_ticks(&mut self, max_rewind_ticks: u64) {
+        self.transforms = TransformHistory::new(max_rewind_ticks);
+    }
+
+    pub fn snapshot(
+        &self,
+        tick: u64,
+        names: &HashMap<usize, String>,
+        pings: &HashMap<usize, u16>,
+    ) -> Snapshot {
+        let players = self
+            .players
+            .iter()
+            .map(|(id, p)| PlayerState {
+                id: *id,
+                name: names.get(id).cloned().unwrap_or_default(),
+                last_input_seq: p.last_input_seq,
+                ping_ms: pings.get(id).copied().unwrap_or(0),
+                pos: p.transform.pos,
+                quat: p.transform.quat,
+                health: p.attributes.health,
+                is_taking_dmg: p.attributes.is_taking_dmg,
+                anim_target_id: p.anim_target_id,
+                anim_ticks: p.anim_ticks,
+                projectile: p.projectile.map(|proj| ProjectileState {
+                    pos: proj.transform.pos,
+                    quat: proj.transform.quat,
+                }),
+            })
+            .collect();
+        Snapshot { tick, players }
+    }
+}
+
+/// the seed of a room from the server seed, the same for the same room name every run
+pub fn room_seed(seed: u64, room: &str) -> u64 {
+    // fnv-1a, the std hashers dont promise to hash the same way in every release
+    room.bytes().fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    const PLAYERS: usize = 4;
+
+    /// a small arena so players spawn close enough to hit each other and walk off the edge
+    fn config() -> SimConfig {
+        SimConfig {
+            tick_duration: Duration::from_millis(17),
+            arena: Arena {
+                spawn_half_extent: 0.5,
+                half_extent: 2.0,
+            },
+            max_rewind_ticks: 10,
+        }
+    }
+
+    /// random but the same every time. inputs are held for a while, attacks only land after
+    /// 20 ticks, and attackers look a few ticks back
+    fn input_log(ticks: u64) -> Vec<TickInputs> {
+        let mut rng = StdRng::seed_from_u64(99);
+        let mut held = vec![PlayerInput::new(); PLAYERS];
+        (1..=ticks)
+            .map(|tick| {
+                for (i, input) in held.iter_mut().enumerate() {
+                    if rng.gen_bool(0.03) {
+                        *input = PlayerInput {
+                            id: i + 1,
+                            seq: 0,
+                            step_forward: rng.gen_bool(0.3),
+                            step_backward: rng.gen_bool(0.2),
+                            step_left: rng.gen_bool(0.2),
+                            step_right: rng.gen_bool(0.3),
+                            kick: rng.gen_bool(0.2),
+                            punch: rng.gen_bool(0.3),
+                            run: rng.gen_bool(0.5),
+                            facing_rad: rng.gen_range(-3.0..3.0),
+                        };
+                    }
+                    input.seq = tick as u32;
+                }
+                TickInputs {
+                    inputs: held.clone(),
+                    view_ticks: (1..=PLAYERS)
+                        .map(|id| (id, tick.saturating_sub(id as u64)))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// the world after the log, and whether anyone was hit and anyone fell off
+    fn run(seed: u64, log: &[TickInputs]) -> (World, bool, bool) {
+        let config = config();
+        let mut world = World::new(seed, config.max_rewind_ticks);
+        for id in 1..=PLAYERS {
+            world.join(id, None, &config.arena);
+        }
+        let (mut hit, mut fell) = (false, false);
+        for (tick, inputs) in (1..).zip(log) {
+            world.step(tick, inputs, &config);
+            for player in world.players.values() {
+                hit |= player.attributes.is_taking_dmg;
+                fell |= player.transform.pos[1] < -7.0;
+            }
+        }
+        (world, hit, fell)
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_the_same_world() {
+        let log = input_log(2000);
+        let (a, hit, fell) = run(7, &log);
+        let (b, _, _) = run(7, &log);
+        assert!(hit && fell, "the log should exercise damage and respawns");
+        assert_eq!(a.players, b.players);
+        // PartialEq on f32 would let 0.0 == -0.0 through
+        let bits = |world: &World| serde_json::to_string(&world.players).unwrap();
+        assert_eq!(bits(&a), bits(&b));
+
+        let (c, _, _) = run(8, &log);
+        assert_ne!(a.players, c.players);
+    }
+}
@@ -1,42 +1,542 @@
-use crate::{messages, server};
+use crate::metrics::{self, Disconnect, Metrics};
+use crate::{config, config::Config, messages, protocol, server};
 use actix::prelude::*;
 use actix_web_actors::ws;
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+use self::latency::Latency;
+pub use self::rate_limit::Penalty;
+use self::rate_limit::TokenBucket;
+use self::strikes::Strikes;
+mod latency;
+mod rate_limit;
+mod strikes;
+
+/// Which rate limit a message counts against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Limit {
+    Chat,
+    Command,
+    Input,
+}
+
+impl Limit {
+    fn of(msg: &messages::ClientMessage) -> Self {
+        use messages::ClientMessage;
+        match msg {
+            ClientMessage::Input(_) | ClientMessage::Ack { .. } => Limit::Input,
+            ClientMessage::Chat { .. } | ClientMessage::Whisper { .. } => Limit::Chat,
+            _ => Limit::Command,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Session {
+    /// public player id, what other clients know this player by
     pub id: usize,
+    /// secret the server knows this session by, set once connected
+    pub token: Option<server::SessionToken>,
+    /// token of an earlier session the client wants to take its player back from
+    pub resume: Option<server::SessionToken>,
+    /// address the client connected from
+    pub ip: Option<IpAddr>,
+    /// the client said hello with a protocol version we speak
+    pub hello: bool,
     pub hb: Instant, //ping-pong heartbeat for timing out clients
-    pub room: String,
-    pub name: Option<String>,
     pub server_addr: Addr<server::Server>,
+    /// the room actor currently simulating this sessions player
+    pub room_addr: Option<Addr<server::Room>>,
+    /// how snapshots are encoded, negotiated at connect
+    pub format: protocol::Format,
+    pub latency: Latency,
+    pub strikes: Strikes,
+    chat_limit: TokenBucket,
+    command_limit: TokenBucket,
+    input_limit: TokenBucket,
+    penalty: Penalty,
+    mute: Duration,
+    muted_until: Option<Instant>,
+    max_chat_len: usize,
+    moderator_token: Option<String>,
+    /// the client sent the moderator token, so it may mute, kick and ban
+    moderator: bool,
+    metrics: Arc<Metrics>,
+    /// why the connection is closing, counted once the session stops
+    disconnect_reason: Option<Disconnect>,
+    /// parent of everything the session logs, with the player id once connected
+    span: tracing::Span,
+    /// tick of the last snapshot sent and when, for clock sync
+    pub last_snapshot: Option<(u64, Instant)>,
+    /// pings are also how latency is measured, so they go out more often than a timeout needs
+    pub heartbeat_interval: Duration,
+    pub client_timeout: Duration,
+    pub tick_duration: Duration,
 }
 
 impl Session {
-    pub fn new(server_addr: Addr<server::Server>) -> Self {
+    pub fn new(
+        server_addr: Addr<server::Server>,
+        format: protocol::Format,
+        resume: Option<server::SessionToken>,
+        ip: Option<IpAddr>,
+        config: &Config,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        use tracing::field;
+        let span = tracing::info_span!("session", id = field::Empty, ip = field::Empty);
+        if let Some(ip) = ip {
+            span.record("ip", field::display(ip));
+        }
         Self {
             id: 0, //owerwrite this on actor started
+            token: None,
+            resume,
+            ip,
+            hello: false,
             hb: Instant::now(),
-            room: String::from("main"),
-            name: None, //
             server_addr,
+            room_addr: None,
+            format,
+            latency: Latency::new(),
+            strikes: Strikes::new(config.max_strikes, config.strike_window()),
+            chat_limit: TokenBucket::new(config.chat_per_second, config.chat_burst),
+            command_limit: TokenBucket::new(config.commands_per_second, config.commands_burst),
+            input_limit: TokenBucket::new(config.inputs_per_second, config.inputs_burst),
+            penalty: config.rate_limit_penalty,
+            mute: config.mute(),
+            muted_until: None,
+            max_chat_len: config.max_chat_len,
+            moderator_token: config.moderator_token.clone(),
+            moderator: false,
+            metrics,
+            disconnect_reason: None,
+            span,
+            last_snapshot: None,
+            heartbeat_interval: config.heartbeat_interval(),
+            client_timeout: config.client_timeout(),
+            tick_duration: config.tick_duration(),
         }
     }
+
+    fn send_player_input(&self, player_input: messages::PlayerInput) {
+        if let Some(room_addr) = &self.room_addr {
+            room_addr.do_send(messages::PlayerInput {
+                id: self.id,
+                ..player_input
+            });
+        }
+    }
+
+    fn send_ack(&self, ctx: &ws::WebsocketContext<Self>, tick: u64) {
+        if let Some(room_addr) = &self.room_addr {
+            room_addr.do_send(messages::SnapshotAckMessage {
+                id: self.id,
+                tick,
+                addr: ctx.address().recipient(),
+            });
+        }
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                //println!("Websocket Client heartbeat failed, disconnecting!");
-                act.server_addr
-                    .do_send(messages::PlayerDisconnectMessage { id: act.id });
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
+                act.disconnect_reason
+                    .get_or_insert(Disconnect::HeartbeatTimeout);
+                act.disconnect();
                 ctx.stop();
                 return;
             }
-            ctx.ping(b"");
+            ctx.ping(&act.latency.ping_payload());
         });
     }
+
+    /// take the player out of the world, safe to call more than once
+    fn disconnect(&self) {
+        if let Some(token) = self.token {
+            self.server_addr
+                .do_send(messages::PlayerDisconnectMessage { token });
+        }
+    }
+
+    /// tell the room our ping so it can go in the snapshots
+    fn send_ping(&self) {
+        if let (Some(room_addr), Some(rtt)) = (&self.room_addr, self.latency.rtt()) {
+            room_addr.do_send(messages::PlayerPingMessage {
+                id: self.id,
+                rtt_ms: rtt.as_millis().min(u16::MAX as u128) as u16,
+            });
+        }
+    }
+
+    fn send(&self, ctx: &mut ws::WebsocketContext<Self>, msg: &messages::ServerMessage) {
+        match serde_json::to_string(msg) {
+            Ok(s) => {
+                metrics::inc(&self.metrics.text_out);
+                ctx.text(s)
+            }
+            Err(e) => tracing::error!(parent: &self.span, ?msg, error = %e, "failed to encode"),
+        }
+    }
+
+    /// close the websocket with a code and a reason the client can show
+    fn close(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        why: Disconnect,
+        code: ws::CloseCode,
+        reason: &str,
+    ) {
+        tracing::info!(parent: &self.span, ?code, reason, "closing");
+        self.disconnect_reason.get_or_insert(why);
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(reason.to_owned()),
+        }));
+        ctx.stop();
+    }
+
+    /// tell the client what was wrong and count a strike, too many and it is disconnected
+    fn reject(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        code: messages::ErrorCode,
+        rejected_kind: Option<&str>,
+        detail: String,
+    ) {
+        tracing::debug!(parent: &self.span, ?code, rejected_kind, detail, "rejected message");
+        let msg = messages::ServerMessage::Error {
+            code,
+            rejected_kind: rejected_kind.map(str::to_owned),
+            detail,
+        };
+        self.send(ctx, &msg);
+        if self.strikes.add() {
+            let why = Disconnect::BadInput;
+            self.close(ctx, why, ws::CloseCode::Policy, "too many bad messages");
+        }
+    }
+
+    /// false if the text cant be said, after telling the client why
+    fn check_chat(&mut self, ctx: &mut ws::WebsocketContext<Self>, kind: &str, text: &str) -> bool {
+        use messages::{ErrorCode, ServerMessage};
+
+        if text.is_empty() {
+            self.reject(
+                ctx,
+                ErrorCode::InvalidArgument,
+                Some(kind),
+                String::from("text is empty"),
+            );
+            return false;
+        }
+        if text.chars().count() > self.max_chat_len {
+            let detail = format!("text is longer than {} chars", self.max_chat_len);
+            self.reject(ctx, ErrorCode::InvalidArgument, Some(kind), detail);
+            return false;
+        }
+        if let Some(left) = self
+            .muted_until
+            .and_then(|until| until.checked_duration_since(Instant::now()))
+        {
+            let msg = ServerMessage::Error {
+                code: ErrorCode::Muted,
+                rejected_kind: Some(kind.to_owned()),
+                detail: format!("muted for {}s", left.as_secs() + 1),
+            };
+            self.send(ctx, &msg);
+            return false;
+        }
+        true
+    }
+
+    /// count a message against its rate limit. false if it went over, after the penalty is applied
+    fn within_limit(&mut self, ctx: &mut ws::WebsocketContext<Self>, limit: Limit) -> bool {
+        let bucket = match limit {
+            Limit::Chat => &mut self.chat_limit,
+            Limit::Command => &mut self.command_limit,
+            Limit::Input => &mut self.input_limit,
+        };
+        if bucket.take() {
+            return true;
+        }
+        match self.penalty {
+            Penalty::Drop => (),
+            Penalty::Mute => self.muted_until = Some(Instant::now() + self.mute),
+            Penalty::Disconnect => {
+                let why = Disconnect::RateLimited;
+                self.close(ctx, why, ws::CloseCode::Policy, "rate limit exceeded");
+                return false;
+            }
+        }
+        // an error for every dropped input would be a flood of its own
+        if limit != Limit::Input {
+            let mut detail = format!("too many {limit:?} messages").to_lowercase();
+            if self.penalty == Penalty::Mute {
+                detail += &format!(", chat muted for {}s", self.mute.as_secs());
+            }
+            let msg = messages::ServerMessage::Error {
+                code: messages::ErrorCode::RateLimited,
+                rejected_kind: None,
+                detail,
+            };
+            self.send(ctx, &msg);
+        }
+        false
+    }
+
+    /// hand a moderator command to the server and tell the client how it went
+    fn moderate(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        kind: &'static str,
+        player_id: usize,
+        action: messages::ModAction,
+    ) {
+        use messages::{ErrorCode, ServerMessage};
+
+        if !self.moderator {
+            self.reject(
+                ctx,
+                ErrorCode::NotModerator,
+                Some(kind),
+                String::from("not a moderator"),
+            );
+            return;
+        }
+        self.server_addr
+            .send(messages::ModerateMessage {
+                by: format!("moderator {}", self.id),
+                player_id,
+                action,
+            })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                let msg = match res {
+                    Ok(Ok(text)) => ServerMessage::System { text },
+                    Ok(Err(detail)) => ServerMessage::Error {
+                        code: ErrorCode::NoSuchPlayer,
+                        rejected_kind: Some(kind.to_owned()),
+                        detail,
+                    },
+                    Err(_) => ServerMessage::Error {
+                        code: ErrorCode::NoSuchPlayer,
+                        rejected_kind: Some(kind.to_owned()),
+                        detail: String::from("try again"),
+                    },
+                };
+                act.send(ctx, &msg);
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    fn handle_client_message(
+        &mut self,
+        msg: messages::ClientMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        use messages::{ClientMessage, ErrorCode, ServerMessage};
+
+        if !self.hello {
+            match msg {
+                ClientMessage::Hello { version } if version == protocol::VERSION => {
+                    self.hello = true
+                }
+                ClientMessage::Hello { version } => {
+                    // nothing else the client sends will make sense either
+                    let detail = format!(
+                        "client speaks protocol {version}, server speaks {}",
+                        protocol::VERSION
+                    );
+                    let msg = ServerMessage::Error {
+                        code: ErrorCode::UnsupportedVersion,
+                        rejected_kind: Some(String::from("hello")),
+                        detail: detail.clone(),
+                    };
+                    self.send(ctx, &msg);
+                    let why = Disconnect::BadInput;
+                    self.close(ctx, why, ws::CloseCode::Unsupported, &detail);
+                }
+                msg => self.reject(
+                    ctx,
+                    ErrorCode::HelloRequired,
+                    Some(msg.kind()),
+                    String::from("say hello first"),
+                ),
+            }
+            return;
+        }
+
+        match msg {
+            ClientMessage::Hello { .. } => (),
+            ClientMessage::Input(p) => {
+                //is there a clean way use remaining fields (from a different type)?
+                self.send_player_input(messages::PlayerInput {
+                    id: self.id,
+                    seq: p.seq,
+                    step_forward: p.step_forward,
+                    step_backward: p.step_backward,
+                    step_left: p.step_left,
+                    step_right: p.step_right,
+                    kick: p.kick,
+                    punch: p.punch,
+                    run: p.run,
+                    facing_rad: p.facing_rad,
+                });
+            }
+            ClientMessage::Ack { tick } => self.send_ack(ctx, tick),
+            ClientMessage::Time { client_time } => {
+                //clock sync: echo the clients time with our tick clock and latency
+                let msg = ServerMessage::Time {
+                    client_time,
+                    server_tick: self.server_tick(),
+                    rtt_ms: self.latency.rtt().unwrap_or_default().as_millis() as u64,
+                    jitter_ms: self.latency.jitter().as_millis() as u64,
+                };
+                self.send(ctx, &msg);
+            }
+            ClientMessage::List => {
+                // send() is for when we want to pause processing of new messages until response returned
+                // do_send() is for when we dont care about the response
+                self.server_addr
+                    .send(messages::ListRooms)
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(rooms) => act.send(ctx, &ServerMessage::Rooms { rooms }),
+                            Err(e) => tracing::warn!(parent: &act.span, error = %e, "could not list rooms"),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+            }
+            ClientMessage::Join { room } if room.is_empty() => self.reject(
+                ctx,
+                ErrorCode::InvalidArgument,
+                Some("join"),
+                String::from("room name is empty"),
+            ),
+            ClientMessage::Join { room } => {
+                if let Some(token) = self.token {
+                    self.server_addr
+                        .send(messages::PlayerJoinRoomMessage {
+                            token,
+                            name: room.clone(),
+                        })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            // not the clients fault, so no strike
+                            let failed = |detail| ServerMessage::Error {
+                                code: ErrorCode::JoinFailed,
+                                rejected_kind: Some(String::from("join")),
+                                detail,
+                            };
+                            match res {
+                                Ok(Ok(room_addr)) => {
+                                    act.room_addr = Some(room_addr);
+                                    act.send(ctx, &ServerMessage::Joined { room });
+                                }
+                                Ok(Err(reason)) => act.send(ctx, &failed(reason)),
+                                Err(_) => act.send(ctx, &failed(format!("could not join {room}"))),
+                            }
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+            }
+            ClientMessage::Chat { text } => {
+                if let (true, Some(token)) = (self.check_chat(ctx, "chat", &text), self.token) {
+                    self.server_addr
+                        .do_send(messages::ChatMessage { token, text });
+                }
+            }
+            ClientMessage::Whisper { to, text } => {
+                if let (true, Some(token)) = (self.check_chat(ctx, "whisper", &text), self.token) {
+                    self.server_addr
+                        .do_send(messages::WhisperMessage { token, to, text });
+                }
+            }
+            ClientMessage::Name { name } => {
+                if let Some(token) = self.token {
+                    self.server_addr
+                        .send(messages::SetNameMessage { token, name })
+                        .into_actor(self)
+                        .then(|res, act, ctx| {
+                            // picking a name someone has is not misbehaving, so no strike
+                            let (code, detail) = match res {
+                                Ok(Ok(())) => return fut::ready(()),
+                                Ok(Err(e @ server::NameError::Taken)) => {
+                                    (ErrorCode::NameTaken, e.to_string())
+                                }
+                                Ok(Err(e)) => (ErrorCode::InvalidName, e.to_string()),
+                                Err(_) => (ErrorCode::InvalidName, String::from("try again")),
+                            };
+                            let msg = ServerMessage::Error {
+                                code,
+                                rejected_kind: Some(String::from("name")),
+                                detail,
+                            };
+                            act.send(ctx, &msg);
+                            fut::ready(())
+                        })
+                        .wait(ctx)
+                }
+            }
+            ClientMessage::Moderator { token } => {
+                let ok = matches!(&self.moderator_token, Some(expected) if config::same_secret(expected, &token));
+                if ok {
+                    self.moderator = true;
+                    let text = String::from("you are a moderator");
+                    self.send(ctx, &ServerMessage::System { text });
+                } else {
+                    // guessing costs a strike
+                    self.reject(
+                        ctx,
+                        ErrorCode::NotModerator,
+                        Some("moderator"),
+                        String::from("wrong moderator token"),
+                    );
+                }
+            }
+            ClientMessage::Mute { player_id, secs } => {
+                let action = messages::ModAction::Mute(Duration::from_secs(secs));
+                self.moderate(ctx, "mute", player_id, action)
+            }
+            ClientMessage::Unmute { player_id } => {
+                self.moderate(ctx, "unmute", player_id, messages::ModAction::Unmute)
+            }
+            ClientMessage::Kick { player_id, reason } => {
+                let action = messages::ModAction::Kick { reason };
+                self.moderate(ctx, "kick", player_id, action)
+            }
+            ClientMessage::Ban {
+                player_id,
+                reason,
+                secs,
+                ip,
+            } => {
+                let action = messages::ModAction::Ban {
+                    reason,
+                    duration: secs.map(Duration::from_secs),
+                    ip,
+                };
+                self.moderate(ctx, "ban", player_id, action)
+            }
+        }
+    }
+
+    /// the servers tick clock right now, as fractional ticks, estimated from the last snapshot
+    fn server_tick(&self) -> f64 {
+        match self.last_snapshot {
+            Some((tick, at)) => {
+                tick as f64 + at.elapsed().as_secs_f64() / self.tick_duration.as_secs_f64()
+            }
+            None => 0.0,
+        }
+    }
 }
 
 impl Actor for Session {
@@ -48,17 +548,40 @@ impl Actor for Session {
         let addr = ctx.address();
         self.server_addr
             .send(messages::PlayerConnectMessage {
-                addr: addr.recipient(),
+                addr,
+                resume: self.resume.take(),
+                ip: self.ip,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => {
-                        act.id = res;
-                        let s = format!("meta player_id {}", res);
-                        ctx.text(s);
+                    Ok(Some(messages::Connected {
+                        id,
+                        token,
+                        room,
+                        resumed,
+                    })) => {
+                        act.id = id;
+                        act.span.record("id", id);
+                        act.token = Some(token);
+                        act.room_addr = Some(room);
+                        // a new token every time, the one the client resumed with is spent
+                        let msg = messages::ServerMessage::Welcome {
+                            version: protocol::VERSION,
+                            player_id: id,
+                            resume_token: token.to_string(),
+                            resumed,
+                        };
+                        act.send(ctx, &msg);
+                    }
+                    Ok(None) => {
+                        let why = Disconnect::ServerFull;
+                        act.close(ctx, why, ws::CloseCode::Again, "server is full")
+                    }
+                    Err(_) => {
+                        let why = Disconnect::ServerError;
+                        act.close(ctx, why, ws::CloseCode::Error, "server unavailable")
                     }
-                    _ => ctx.stop(),
                 }
                 fut::ready(())
             })
@@ -66,132 +589,154 @@ impl Actor for Session {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.server_addr
-            .do_send(messages::PlayerDisconnectMessage { id: self.id });
+        let why = self.disconnect_reason.unwrap_or(Disconnect::Dropped);
+        tracing::info!(parent: &self.span, reason = why.label(), "disconnected");
+        self.metrics.disconnect(why);
+        self.disconnect();
         Running::Stop
     }
 }
 
-impl Handler<messages::GameStateMessage> for Session {
+impl Handler<messages::ServerMessage> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::ServerMessage, ctx: &mut Self::Context) {
+        self.send(ctx, &msg);
+    }
+}
+
+impl Handler<messages::CloseSessionMessage> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::CloseSessionMessage, ctx: &mut Self::Context) {
+        self.close(ctx, msg.why, msg.code, &msg.reason);
+    }
+}
+
+impl Handler<messages::RoomChangedMessage> for Session {
     type Result = ();
 
-    fn handle(&mut self, msg: messages::GameStateMessage, ctx: &mut Self::Context) {
-        //println!("session id: {}, sending msg: {}", self.id, msg.0);
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: messages::RoomChangedMessage, ctx: &mut Self::Context) {
+        self.room_addr = Some(msg.addr);
+        self.send(ctx, &messages::ServerMessage::Joined { room: msg.room });
+    }
+}
+
+impl Handler<messages::TickDurationMessage> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::TickDurationMessage, _: &mut Self::Context) {
+        self.tick_duration = msg.tick_duration;
+    }
+}
+
+impl Handler<messages::SnapshotMessage> for Session {
+    type Result = ();
+
+    fn handle(&mut self, msg: messages::SnapshotMessage, ctx: &mut Self::Context) {
+        self.last_snapshot = Some((msg.snapshot.tick, Instant::now()));
+        match msg.snapshot.encode(self.format, msg.baseline.as_deref()) {
+            Some(protocol::Message::Text(s)) => {
+                self.metrics.snapshot_bytes.observe(s.len() as f64);
+                metrics::inc(&self.metrics.snapshots_out);
+                ctx.text(s)
+            }
+            Some(protocol::Message::Binary(b)) => {
+                self.metrics.snapshot_bytes.observe(b.len() as f64);
+                metrics::inc(&self.metrics.snapshots_out);
+                ctx.binary(b)
+            }
+            None => {
+                tracing::error!(parent: &self.span, "failed to encode snapshot, not sending anything")
+            }
+        }
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Session {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
         let msg = match msg {
-            Err(_) => {
-                ctx.stop();
+            Err(ws::ProtocolError::Overflow) => {
+                let why = Disconnect::TooBig;
+                self.close(ctx, why, ws::CloseCode::Size, "message too big");
+                return;
+            }
+            Err(e) => {
+                let why = Disconnect::ProtocolError;
+                self.close(ctx, why, ws::CloseCode::Protocol, &e.to_string());
                 return;
             }
             Ok(msg) => msg,
         };
 
-        //println!("WEBSOCKET MESSAGE: {msg:?}");
+        tracing::trace!(parent: &self.span, ?msg, "websocket message");
+        match msg {
+            ws::Message::Text(_) => metrics::inc(&self.metrics.text_in),
+            ws::Message::Binary(_) => metrics::inc(&self.metrics.binary_in),
+            _ => (),
+        }
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
                 ctx.pong(&msg);
             }
-            ws::Message::Pong(_) => {
+            ws::Message::Pong(payload) => {
                 self.hb = Instant::now();
+                if self.latency.on_pong(&payload) {
+                    self.send_ping();
+                }
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
+            ws::Message::Binary(_) if !self.hello => self.reject(
+                ctx,
+                messages::ErrorCode::HelloRequired,
+                Some("binary"),
+                String::from("say hello first"),
+            ),
+            ws::Message::Binary(_) if !self.within_limit(ctx, Limit::Input) => (),
+            ws::Message::Binary(bytes) => match protocol::client_frame_from_bytes(&bytes) {
+                Some(protocol::ClientFrame::Input(player_input)) => {
+                    self.send_player_input(player_input)
+                }
+                Some(protocol::ClientFrame::Ack(tick)) => self.send_ack(ctx, tick),
+                None => self.reject(
+                    ctx,
+                    messages::ErrorCode::BadFrame,
+                    Some("binary"),
+                    format!(
+                        "not an input or ack frame of protocol {}",
+                        protocol::VERSION
+                    ),
+                ),
+            },
             ws::Message::Close(reason) => {
+                self.disconnect_reason
+                    .get_or_insert(Disconnect::ClientClosed);
                 ctx.close(reason);
                 ctx.stop();
             }
             ws::Message::Continuation(_) => {
-                ctx.stop();
+                let why = Disconnect::ProtocolError;
+                self.close(ctx, why, ws::CloseCode::Unsupported, "fragmented frames");
             }
             ws::Message::Nop => (),
             ws::Message::Text(text) => {
-                let m = text.trim();
-                // check for special commands, else just send text to server to process
-                if m.starts_with('/') {
-                    let (cmd, arg) = m.split_once(" ").unwrap_or((m, ""));
-                    match cmd {
-                        "/list" => {
-                            //list rooms
-                            // send() is for when we want to pause processing of new messages until response returned
-                            // do_send() is for when we dont care about the response
-                            println!("List rooms");
-                            self.server_addr
-                                .send(messages::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            //send multiple strings to client
-                                            //for room in rooms {
-                                            //  ctx.text(room);
-                                            //}
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                        }
-                        "/join" => {
-                            //join room
-                            if arg != "" {
-                                self.room = arg.to_owned();
-                                self.server_addr.do_send(messages::PlayerJoinRoomMessage {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                //ctx.text("joined");
-                            }
+                match serde_json::from_str::<messages::ClientMessage>(&text) {
+                    Ok(msg) => {
+                        if self.within_limit(ctx, Limit::of(&msg)) {
+                            self.handle_client_message(msg, ctx)
                         }
-                        "/chat" => {
-                            //chat message
-                            if arg != "" {
-                                self.room = arg.to_owned();
-                                self.server_addr.do_send(messages::ChatMessage {
-                                    id: self.id,
-                                    text: arg.to_string(),
-                                });
-                                //ctx.text("joined");
-                            }
-                        }
-                        "/name" => {
-                            //change name
-                            if arg != "" {
-                                self.name = Some(arg.to_owned());
-                            }
-                        }
-                        _ => println!("unknown slash command recieved, cmd: {cmd}, arg: {arg}"),
                     }
-                } else {
-                    let res: Result<messages::PlayerInputWithoutId, serde_json::Error> =
-                        serde_json::from_str(m); //this fails unless sending id...
-                    match res {
-                        Ok(p) => {
-                            //is there a clean way use remaining fields (from a different type)?
-                            let player_input = messages::PlayerInput {
-                                id: self.id,
-                                step_forward: p.step_forward,
-                                step_backward: p.step_backward,
-                                step_left: p.step_left,
-                                step_right: p.step_right,
-                                kick: p.kick,
-                                punch: p.punch,
-                                run: p.run,
-                                facing_rad: p.facing_rad,
-                            };
-                            self.server_addr.do_send(player_input);
-                        }
-                        Err(_) => {
-                            println!("session, bad player input recieved");
-                            //println!("message: {m}");
-                            ctx.stop();
-                        }
+                    Err(e) => {
+                        // say which message it was, if it at least has a kind
+                        let kind = serde_json::from_str::<serde_json::Value>(&text)
+                            .ok()
+                            .and_then(|v| Some(v.get("kind")?.as_str()?.to_owned()));
+                        self.reject(
+                            ctx,
+                            messages::ErrorCode::BadMessage,
+                            kind.as_deref(),
+                            e.to_string(),
+                        );
                     }
                 }
             }
@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+/// Smoothed round trip time and jitter from timestamped pings (same filter as TCP, RFC 6298)
+#[derive(Debug)]
+pub struct Latency {
+    /// ping payloads are microseconds since this
+    epoch: Instant,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    /// payload for the next ping
+    pub fn ping_payload(&self) -> [u8; 8] {
+        (self.epoch.elapsed().as_micros() as u64).to_le_bytes()
+    }
+
+    /// feed the payload of a pong back, returns false if it wasnt one of our pings
+    pub fn on_pong(&mut self, payload: &[u8]) -> bool {
+        let sent = match <[u8; 8]>::try_from(payload) {
+            Ok(bytes) => Duration::from_micros(u64::from_le_bytes(bytes)),
+            Err(_) => return false,
+        };
+        let now = self.epoch.elapsed();
+        if sent > now {
+            return false;
+        }
+        self.update(now - sent);
+        true
+    }
+
+    fn update(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let err = srtt.max(sample) - srtt.min(sample);
+                self.rttvar = self.rttvar * 3 / 4 + err / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn the_first_sample_sets_the_rtt_and_half_of_it_as_jitter() {
+        let mut latency = Latency::new();
+        assert_eq!(latency.rtt(), None);
+        latency.update(MS * 100);
+        assert_eq!(latency.rtt(), Some(MS * 100));
+        assert_eq!(latency.jitter(), MS * 50);
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut latency = Latency::new();
+        latency.update(MS * 100);
+        latency.update(MS * 200);
+        // srtt 7/8 * 100 + 1/8 * 200, rttvar 3/4 * 50 + 1/4 * |100 - 200|
+        assert_eq!(latency.rtt(), Some(Duration::from_micros(112_500)));
+        assert_eq!(latency.jitter(), Duration::from_micros(62_500));
+        // a faster sample pulls both down
+        latency.update(MS * 12);
+        assert_eq!(
+            latency.rtt(),
+            Some(Duration::from_nanos(112_500_000 * 7 / 8 + 1_500_000))
+        );
+        assert_eq!(
+            latency.jitter(),
+            Duration::from_nanos(62_500_000 * 3 / 4 + 100_500_000 / 4)
+        );
+    }
+
+    #[test]
+    fn only_our_own_pings_count() {
+        let mut latency = Latency::new();
+        assert!(!latency.on_pong(b"pong"));
+        let future = (latency.epoch.elapsed() + Duration::from_secs(60)).as_micros() as u64;
+        assert!(!latency.on_pong(&future.to_le_bytes()));
+        assert_eq!(latency.rtt(), None);
+
+        assert!(latency.on_pong(&latency.ping_payload()));
+        assert!(latency.rtt().is_some());
+    }
+}
@@ -0,0 +1,94 @@
+use serde::Deserialize;
+use std::time::Instant;
+
+/// What happens to a client that goes over a rate limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Penalty {
+    /// drop what went over the limit
+    Drop,
+    /// drop it and ignore chat from the client for a while
+    Mute,
+    /// close the connection
+    Disconnect,
+}
+
+/// Allows `burst` messages at once, refilled at `per_second`
+#[derive(Debug)]
+pub struct TokenBucket {
+    burst: f64,
+    per_second: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_second: f64, burst: f64) -> Self {
+        Self {
+            burst,
+            per_second,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// take a token, false if there is none left
+    pub fn take(&mut self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> bool {
+        let refill = (now - self.last).as_secs_f64() * self.per_second;
+        self.tokens = (self.tokens + refill).min(self.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn a_full_bucket_allows_a_burst_then_nothing() {
+        let mut bucket = TokenBucket::new(10.0, 3.0);
+        let now = bucket.last;
+        for _ in 0..3 {
+            assert!(bucket.take_at(now));
+        }
+        assert!(!bucket.take_at(now));
+        // refused takes dont dig the bucket deeper
+        assert!(!bucket.take_at(now));
+        assert!(bucket.take_at(now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn tokens_come_back_at_the_rate() {
+        let mut bucket = TokenBucket::new(10.0, 3.0);
+        let start = bucket.last;
+        let at = |ms| start + Duration::from_millis(ms);
+        for _ in 0..3 {
+            assert!(bucket.take_at(start));
+        }
+        // 1 token per 100ms
+        assert!(!bucket.take_at(at(99)));
+        assert!(bucket.take_at(at(100)));
+        assert!(!bucket.take_at(at(150)));
+        assert!(bucket.take_at(at(200)));
+    }
+
+    #[test]
+    fn idle_time_refills_no_more_than_the_burst() {
+        let mut bucket = TokenBucket::new(10.0, 3.0);
+        let later = bucket.last + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.take_at(later));
+        }
+        assert!(!bucket.take_at(later));
+    }
+}
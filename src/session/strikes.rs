@@ -0,0 +1,73 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Counts the bad messages of a client. Up to `max` within any `window` are forgiven,
+/// one more and the client is disconnected.
+#[derive(Debug)]
+pub struct Strikes {
+    max: u32,
+    window: Duration,
+    /// when the strikes still in the window were counted, oldest first
+    times: VecDeque<Instant>,
+}
+
+impl Strikes {
+    pub fn new(max: u32, window: Duration) -> Self {
+        Self {
+            max,
+            window,
+            times: VecDeque::new(),
+        }
+    }
+
+    /// count a strike, returns true if that was one too many
+    pub fn add(&mut self) -> bool {
+        self.add_at(Instant::now())
+    }
+
+    fn add_at(&mut self, now: Instant) -> bool {
+        while matches!(self.times.front(), Some(t) if now - *t >= self.window) {
+            self.times.pop_front();
+        }
+        self.times.push_back(now);
+        self.times.len() > self.max as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn max_strikes_are_forgiven_and_one_more_is_not() {
+        let start = Instant::now();
+        let mut strikes = Strikes::new(3, WINDOW);
+        for i in 0..3 {
+            assert!(!strikes.add_at(start + Duration::from_secs(i)));
+        }
+        assert!(strikes.add_at(start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn strikes_leave_the_window_one_by_one() {
+        let start = Instant::now();
+        let at = |s| start + Duration::from_secs(s);
+        let mut strikes = Strikes::new(2, WINDOW);
+        assert!(!strikes.add_at(at(0)));
+        assert!(!strikes.add_at(at(50)));
+        // the first one is out of the window, the second is not
+        assert!(!strikes.add_at(at(60)));
+        assert!(strikes.add_at(at(61)));
+    }
+
+    #[test]
+    fn a_steady_trickle_never_resets_the_count() {
+        let start = Instant::now();
+        let mut strikes = Strikes::new(3, WINDOW);
+        // a strike every 15s is 4 in every minute, gaps never get longer than the window
+        let out = (0..8).find(|i| strikes.add_at(start + Duration::from_secs(i * 15)));
+        assert_eq!(out, Some(3));
+    }
+}
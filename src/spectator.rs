@@ -0,0 +1,87 @@
+use crate::messages::ServerMessage;
+use crate::protocol::{self, Format};
+use crate::server::Playback;
+use actix::prelude::*;
+use actix_web_actors::ws;
+use std::time::Duration;
+
+/// a quiet stretch in a replay, like an empty room, is skipped after this long
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// Plays a replay to a websocket at the speed it was recorded, in the same snapshots a
+/// client in the room got. It is not a player, anything it sends is ignored
+pub struct Spectator {
+    playback: Playback,
+    format: Format,
+    last_tick: Option<u64>,
+}
+
+impl Spectator {
+    pub fn new(playback: Playback, format: Format) -> Self {
+        Self {
+            playback,
+            format,
+            last_tick: None,
+        }
+    }
+
+    /// simulate the next tick and send it when it is due
+    fn play(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let snapshot = match self.playback.next_tick() {
+            Some(snapshot) => snapshot,
+            None => {
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Normal,
+                    description: Some(String::from("end of replay")),
+                }));
+                ctx.stop();
+                return;
+            }
+        };
+        let wait = match self.last_tick {
+            Some(last) => {
+                let ticks = snapshot.tick.saturating_sub(last).min(u32::MAX as u64) as u32;
+                (self.playback.tick_duration() * ticks).min(MAX_WAIT)
+            }
+            None => Duration::ZERO,
+        };
+        self.last_tick = Some(snapshot.tick);
+        ctx.run_later(wait, move |act, ctx| {
+            match snapshot.encode(act.format, None) {
+                Some(protocol::Message::Text(s)) => ctx.text(s),
+                Some(protocol::Message::Binary(b)) => ctx.binary(b),
+                None => tracing::error!("failed to encode snapshot, not sending anything"),
+            }
+            act.play(ctx);
+        });
+    }
+}
+
+impl Actor for Spectator {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let joined = ServerMessage::Joined {
+            room: self.playback.room().to_owned(),
+        };
+        match serde_json::to_string(&joined) {
+            Ok(s) => ctx.text(s),
+            Err(e) => tracing::error!(error = %e, "failed to encode joined message"),
+        }
+        self.play(ctx);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Spectator {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => (),
+            Err(_) => ctx.stop(),
+        }
+    }
+}